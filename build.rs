@@ -1 +1,6 @@
-fn main() {}
+fn main() {
+    // Only link the defmt linker script when defmt is compiled in
+    if std::env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+}
@@ -0,0 +1,42 @@
+//! Logging shim over `defmt`
+//!
+//! With the `defmt` feature (the default) these are the `defmt` macros. Without
+//! it they compile to nothing, so the driver carries no logging code or
+//! strings. The arguments are still borrowed so that values which are only
+//! logged don't trigger unused-variable warnings.
+
+#[cfg(feature = "defmt")]
+pub(crate) use defmt::{debug, error, info, warn};
+
+#[cfg(not(feature = "defmt"))]
+mod noop {
+    macro_rules! log_noop {
+        ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+            $(let _ = &$arg;)*
+        }};
+    }
+
+    macro_rules! log_debug {
+        ($($t:tt)*) => { $crate::logging::log_noop!($($t)*) };
+    }
+
+    macro_rules! log_info {
+        ($($t:tt)*) => { $crate::logging::log_noop!($($t)*) };
+    }
+
+    macro_rules! log_warn {
+        ($($t:tt)*) => { $crate::logging::log_noop!($($t)*) };
+    }
+
+    macro_rules! log_error {
+        ($($t:tt)*) => { $crate::logging::log_noop!($($t)*) };
+    }
+
+    // Renamed on export since `warn` clashes with the built-in attribute
+    pub(crate) use {
+        log_debug as debug, log_error as error, log_info as info, log_noop, log_warn as warn,
+    };
+}
+
+#[cfg(not(feature = "defmt"))]
+pub(crate) use noop::{debug, error, info, log_noop, warn};
@@ -1,21 +1,84 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// The host test build has no `main`, so most of the firmware goes unused
+#![cfg_attr(test, allow(unused))]
 
+#[cfg(feature = "defmt")]
 use defmt_rtt as _; // global logger
+#[cfg(not(test))]
 use panic_halt as _;
 
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::{cell::RefCell, fmt::Write};
+use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
-use embedded_hal::spi::{Mode, Phase, Polarity};
-use stm32l4xx_hal::{delay::Delay, interrupt, pac, prelude::*, spi::Spi, timer::Timer};
+use heapless::String;
+use stm32l4xx_hal::{
+    delay::Delay,
+    dma::DmaExt,
+    gpio::{Edge, ExtiPin},
+    interrupt, pac,
+    prelude::*,
+    rcc::{ClockSecuritySystem, CrystalBypass},
+    rtc::{Rtc, RtcClockSource, RtcConfig},
+    time::MilliSeconds,
+    timer::Timer,
+    watchdog::IndependentWatchdog,
+};
+
+mod logging;
+mod rtc;
+mod status_led;
+mod time;
+mod wifi;
+
+#[cfg(not(feature = "board-b-l475e-iot01a"))]
+compile_error!("main.rs runs on the Discovery board; enable `board-b-l475e-iot01a`");
 
 // Logging macros
-use defmt::*;
+use logging::{error, info, warn};
+use status_led::{LinkStatus, StatusLed};
 
-mod wifi;
+/// Period of the main loop, short enough for the LED's fast blink
+const MAIN_LOOP_PERIOD_MS: u16 = 50;
+
+/// Interval between heartbeat log messages in the main loop
+const HEARTBEAT_INTERVAL_MS: u32 = 5_000;
+
+/// Where the main loop posts its telemetry; off (`None`) by default
+///
+/// To turn it on, point it at an HTTP server that accepts JSON POSTs, e.g.:
+///
+/// ```ignore
+/// const TELEMETRY_ENDPOINT: Option<wifi::HttpEndpoint<'static>> = Some(wifi::HttpEndpoint {
+///     host: "telemetry.example.com",
+///     port: 80,
+///     path: "/api/telemetry",
+/// });
+/// ```
+const TELEMETRY_ENDPOINT: Option<wifi::HttpEndpoint<'static>> = None;
+
+/// Interval between link checks in the main loop, which reconnect if down
+const LINK_CHECK_INTERVAL_MS: u32 = 10_000;
+
+/// Interval between telemetry posts in the main loop
+const TELEMETRY_INTERVAL_MS: u32 = 60_000;
 
-// Global timestamp counter (milliseconds since boot)
-static TIMESTAMP_MS: AtomicU32 = AtomicU32::new(0);
+/// Time the self-test allows the echo server to send the payload back
+const ECHO_TIMEOUT_MS: u32 = 2_000;
+
+// Independent watchdog, shared so the WiFi driver can feed it while blocking
+static WATCHDOG: Mutex<RefCell<Option<IndependentWatchdog>>> = Mutex::new(RefCell::new(None));
+
+/// Watchdog timeout; comfortably above the longest gap between feeds
+const WATCHDOG_TIMEOUT_MS: u32 = 4_000;
+
+fn feed_watchdog() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow_mut().as_mut() {
+            watchdog.feed();
+        }
+    });
+}
 
 // TIM2 interrupt handler for timestamp
 #[interrupt]
@@ -25,12 +88,32 @@ fn TIM2() {
         let tim2 = &*pac::TIM2::ptr();
         tim2.sr.modify(|_, w| w.uif().clear_bit());
     }
-    TIMESTAMP_MS.fetch_add(1, Ordering::Relaxed);
+    time::tick();
+}
+
+// EXTI1 interrupt handler for the WiFi data-ready line (PE1)
+#[interrupt]
+fn EXTI1() {
+    // Clear the pending bit for line 1
+    unsafe {
+        let exti = &*pac::EXTI::ptr();
+        exti.pr1.write(|w| w.bits(1 << 1));
+    }
+    wifi::notify_data_ready();
+}
+
+// DMA2 channel 1 interrupt handler: WiFi SPI bulk transfer complete
+#[interrupt]
+fn DMA2_CH1() {
+    wifi::dma::notify_transfer_complete();
 }
 
-// defmt timestamp function - returns milliseconds since boot
-defmt::timestamp!("{=u32:ms}", { TIMESTAMP_MS.load(Ordering::Relaxed) });
+// defmt timestamp function - wall-clock time once synced over NTP, else
+// time since boot (shown as an offset from 1970-01-01)
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u64:iso8601ms}", { time::unix_time_ms() });
 
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     info!("STM32L475 WiFi Application Starting...");
@@ -38,7 +121,7 @@ fn main() -> ! {
 
     // Get access to the device specific peripherals
     let dp = pac::Peripherals::take().unwrap();
-    let cp = cortex_m::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
 
     info!("Peripherals initialized");
 
@@ -47,7 +130,28 @@ fn main() -> ! {
     let mut flash = dp.FLASH.constrain();
     let mut rcc = dp.RCC.constrain();
     let mut pwr = dp.PWR.constrain(&mut rcc.apb1r1);
-    let clocks = rcc.cfgr.freeze(&mut flash.acr, &mut pwr);
+    // The 32.768kHz LSE crystal clocks the RTC so it keeps time across resets
+    let clocks = rcc
+        .cfgr
+        .lse(CrystalBypass::Disable, ClockSecuritySystem::Disable)
+        .freeze(&mut flash.acr, &mut pwr);
+
+    // Resume wall-clock time from the RTC if an earlier boot synced it
+    let rtc = Rtc::rtc(
+        dp.RTC,
+        &mut rcc.apb1r1,
+        &mut rcc.bdcr,
+        &mut pwr.cr1,
+        RtcConfig::default().clock_config(RtcClockSource::LSE),
+    );
+    if let Some(unix_secs) = rtc::init(rtc) {
+        info!(
+            "Restored time from RTC: {} s since the Unix epoch",
+            unix_secs
+        );
+    } else {
+        info!("RTC not set yet; timestamps count from boot");
+    }
 
     // Configure TIM2 for 1ms timestamp interrupts
     let mut timer = Timer::tim2(dp.TIM2, 1000.Hz(), clocks, &mut rcc.apb1r1);
@@ -58,6 +162,9 @@ fn main() -> ! {
         cortex_m::peripheral::NVIC::unmask(pac::Interrupt::TIM2);
     }
 
+    // Microsecond timestamps for profiling SPI transfers and commands
+    time::enable_micros(&mut cp.DCB, &mut cp.DWT, clocks.sysclk().raw());
+
     // Create a delay abstraction based on SysTick
     let mut delay = Delay::new(cp.SYST, clocks);
     info!("System clock and timestamp timer configured successfully");
@@ -65,65 +172,53 @@ fn main() -> ! {
     // Configure GPIO ports
     info!("Configuring GPIO ports...");
     let mut gpioa = dp.GPIOA.split(&mut rcc.ahb2);
-    let mut gpiob = dp.GPIOB.split(&mut rcc.ahb2);
-    let mut gpioc = dp.GPIOC.split(&mut rcc.ahb2);
-    let mut gpioe = dp.GPIOE.split(&mut rcc.ahb2);
+    let gpiob = dp.GPIOB.split(&mut rcc.ahb2);
+    let gpioc = dp.GPIOC.split(&mut rcc.ahb2);
+    let gpioe = dp.GPIOE.split(&mut rcc.ahb2);
 
-    // Configure PA5 as output (LD1 on STM32L475 Discovery)
-    let mut led = gpioa
+    // Configure PA5 as output (LD1 on STM32L475 Discovery); it shows the
+    // WiFi status
+    let led = gpioa
         .pa5
         .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+    let mut status_led = StatusLed::new(led);
     info!("LED configured on PA5");
 
-    // Configure WiFi SPI pins (AF6 for SPI3)
-    let sck =
-        gpioc
-            .pc10
-            .into_alternate_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
-    let miso =
-        gpioc
-            .pc11
-            .into_alternate_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
-    let mosi =
-        gpioc
-            .pc12
-            .into_alternate_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
-
-    // Configure WiFi control pins
-    let wifi_cs = gpioe
-        .pe0
-        .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
-    let wifi_reset = gpioe
-        .pe8
-        .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
-    let wifi_wakeup = gpiob
-        .pb13
-        .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper);
-    let wifi_data_ready = gpioe
-        .pe1
-        .into_pull_up_input(&mut gpioe.moder, &mut gpioe.pupdr);
-
-    // Configure SPI3 for WiFi module
-    let spi = Spi::spi3(
+    // Create WiFi module on SPI3 and its control pins; bulk socket payloads
+    // are clocked by DMA2 channels 1 (RX) and 2 (TX)
+    let dma2 = dp.DMA2.split(&mut rcc.ahb1);
+    let spi_buffer =
+        cortex_m::singleton!(: [u8; wifi::SPI_BULK_SIZE] = [0; wifi::SPI_BULK_SIZE]).unwrap();
+    let mut wifi = wifi::DiscoveryWifiModule::from_discovery(
         dp.SPI3,
-        (sck, miso, mosi),
-        Mode {
-            polarity: Polarity::IdleLow,
-            phase: Phase::CaptureOnFirstTransition,
+        wifi::DiscoveryPorts {
+            gpiob,
+            gpioc,
+            gpioe,
         },
-        1.MHz(),
+        (dma2.1, dma2.2),
+        spi_buffer,
         clocks,
         &mut rcc.apb1r1,
     );
 
-    // Create WiFi module
-    let wifi_pins = wifi::WifiPins {
-        cs: wifi_cs,
-        reset: wifi_reset,
-        wakeup: wifi_wakeup,
-        data_ready: wifi_data_ready,
-    };
-    let mut wifi = wifi::WifiModule::new(spi, wifi_pins);
+    // Wake the core on the rising edge of data ready (EXTI1)
+    let mut syscfg = dp.SYSCFG;
+    let mut exti = dp.EXTI;
+    let wifi_data_ready = &mut wifi.pins.data_ready;
+    wifi_data_ready.make_interrupt_source(&mut syscfg, &mut rcc.apb2);
+    wifi_data_ready.trigger_on_edge(&mut exti, Edge::Rising);
+    wifi_data_ready.enable_interrupt(&mut exti);
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::EXTI1);
+    }
+    wifi.set_watchdog_feed(feed_watchdog);
+
+    // Start the watchdog now that every blocking WiFi call keeps it fed
+    let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+    watchdog.start(MilliSeconds::millis(WATCHDOG_TIMEOUT_MS));
+    cortex_m::interrupt::free(|cs| WATCHDOG.borrow(cs).replace(Some(watchdog)));
+    info!("Watchdog started with {}ms timeout", WATCHDOG_TIMEOUT_MS);
 
     // Initialize WiFi module
     info!("Initializing WiFi module...");
@@ -151,28 +246,177 @@ fn main() -> ! {
     // Replace with your actual WiFi credentials
     let ssid = "Subway";
     let password = "5$FootLong";
+    let config = wifi::ConnectConfig::new(ssid)
+        .password(password)
+        .security(wifi::SecurityMode::Wpa2Psk);
 
     info!("Attempting to connect to WiFi network: {}", ssid);
-    match wifi.connect_to_network(ssid, password, &mut delay) {
-        Ok(_) => info!("WiFi connection successful"),
-        Err(_) => {
-            error!("Failed to connect to WiFi network...");
-            loop {}
+    status_led.set_status(LinkStatus::Connecting);
+    status_led.tick();
+    // Set while the last (re)connect failed; the LED then blinks an error
+    let mut link_failed = false;
+    match wifi.connect(&config, &mut delay) {
+        Ok(_) => {
+            info!("WiFi connection successful");
+            match wifi.get_ip_config() {
+                Ok(config) => info!(
+                    "IP {} netmask {} gateway {} DNS {}",
+                    config.ip, config.netmask, config.gateway, config.dns1
+                ),
+                Err(e) => warn!("Could not read IP configuration: {}", e),
+            }
+        }
+        Err(e) => {
+            // The main loop keeps retrying with `reconnect`
+            error!("Failed to connect to WiFi network: {}", e);
+            link_failed = true;
         }
     }
 
-    // Main loop - slow blink to show system is running
+    // Without a link every check would only run into its timeout, so they
+    // are skipped; recovery is left to the main loop's link check
+    if !link_failed {
+        network_self_test(&mut wifi);
+    }
+
+    // Main loop - the LED follows the WiFi state
     info!("Entering main loop - system operational");
     let mut loop_count = 0u32;
+    let mut last_heartbeat = time::now_ms();
+    let mut last_telemetry = time::now_ms();
+    let mut last_link_check = time::now_ms();
     loop {
-        led.set_high();
-        delay.delay_ms(500u16);
-        led.set_low();
-        delay.delay_ms(500u16);
+        feed_watchdog();
+        if link_failed && !wifi.is_connected() {
+            status_led.set_status(LinkStatus::Error);
+        } else {
+            status_led.set_status(wifi.state());
+        }
+        status_led.tick();
+        delay.delay_ms(MAIN_LOOP_PERIOD_MS);
 
         loop_count += 1;
-        if loop_count % 5 == 0 {
+        if time::elapsed_ms(last_heartbeat) >= HEARTBEAT_INTERVAL_MS {
+            last_heartbeat = time::now_ms();
             info!("System heartbeat - loop count: {}", loop_count);
+            if wifi.is_connected() {
+                match wifi.signal_strength() {
+                    Ok(rssi) => info!("WiFi signal strength: {} dBm", rssi),
+                    Err(e) => warn!("Could not read WiFi signal strength: {}", e),
+                }
+            } else {
+                warn!("WiFi state: {}", wifi.state());
+            }
+        }
+
+        if time::elapsed_ms(last_link_check) >= LINK_CHECK_INTERVAL_MS {
+            match wifi.check_link() {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("WiFi link is down, reconnecting...");
+                    status_led.set_status(LinkStatus::Connecting);
+                    status_led.tick();
+                    link_failed = match wifi.reconnect(&mut delay) {
+                        Ok(()) => false,
+                        Err(e) => {
+                            error!("Reconnect failed: {}", e);
+                            true
+                        }
+                    };
+                }
+                Err(e) => warn!("WiFi link check failed: {}", e),
+            }
+            last_link_check = time::now_ms();
+        }
+
+        if let Some(endpoint) = &TELEMETRY_ENDPOINT {
+            if wifi.is_connected() && time::elapsed_ms(last_telemetry) >= TELEMETRY_INTERVAL_MS {
+                last_telemetry = time::now_ms();
+                let mut body: String<64> = String::new();
+                // 64 bytes always fits both counters
+                let _ = write!(
+                    body,
+                    "{{\"loop_count\":{},\"uptime_ms\":{}}}",
+                    loop_count,
+                    time::now_ms()
+                );
+                match wifi.post_telemetry(endpoint, body.as_bytes()) {
+                    Ok(status) => info!("Telemetry posted, HTTP status {}", status),
+                    Err(e) => warn!("Telemetry post failed: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Check the network from end to end after the first successful connect
+///
+/// Pings the gateway, resolves a hostname, syncs the clock over NTP and
+/// round-trips a payload through a TCP echo server. Failures are only
+/// logged.
+fn network_self_test(wifi: &mut wifi::DiscoveryWifiModule) {
+    // Check the gateway answers, not just that we are associated
+    if let Err(e) = wifi.verify_connectivity() {
+        warn!("Connectivity check failed: {}", e);
+    }
+
+    // Resolve a well-known host to check DNS is working
+    if let Err(e) = wifi.resolve_hostname("example.com") {
+        warn!("DNS lookup failed: {}", e);
+    }
+
+    // Anchor the log timestamps to wall-clock time
+    match wifi.sync_time("pool.ntp.org") {
+        Ok(unix_secs) => {
+            info!("Time synced: {} s since the Unix epoch", unix_secs);
+            rtc::store_unix_time(unix_secs);
+        }
+        Err(e) => warn!("NTP time sync failed: {}", e),
+    }
+
+    // Round-trip a payload through a TCP echo server
+    // Replace with the address of a reachable echo server
+    let echo_server_ip = wifi::WifiIpv4::new(192, 168, 1, 100);
+    let echo_server_port = 7;
+
+    info!("Testing TCP socket against echo server...");
+    match wifi.open_socket(wifi::Protocol::Tcp, echo_server_ip, echo_server_port) {
+        Ok(socket) => {
+            let payload = b"Hello from STM32L475";
+            match wifi.tcp_send(&socket, payload) {
+                Ok(sent) => {
+                    info!("Sent {} bytes to echo server", sent);
+
+                    // The echo takes a round trip and may arrive in pieces
+                    let mut echo = [0u8; 64];
+                    let mut received = 0;
+                    let started = time::now_ms();
+                    while received < sent && time::elapsed_ms(started) < ECHO_TIMEOUT_MS {
+                        feed_watchdog();
+                        match wifi.tcp_receive(&socket, &mut echo[received..]) {
+                            Ok(read) => received += read,
+                            Err(e) => {
+                                warn!("Socket receive failed: {}", e);
+                                break;
+                            }
+                        }
+                    }
+
+                    if received < sent {
+                        warn!("Echo incomplete: {} of {} bytes", received, sent);
+                    } else if echo[..received] == payload[..sent] {
+                        info!("Echo reply matches ({} bytes)", received);
+                    } else {
+                        warn!("Echo reply differs: {=[u8]:a}", &echo[..received]);
+                    }
+                }
+                Err(e) => warn!("Socket send failed: {}", e),
+            }
+
+            if let Err(e) = wifi.close_socket(socket) {
+                warn!("Failed to close socket: {}", e);
+            }
         }
+        Err(e) => warn!("Failed to open socket: {}", e),
     }
 }
@@ -0,0 +1,110 @@
+//! Wall-clock time kept in the RTC so it survives resets
+//!
+//! `store_unix_time` writes a synced time into the RTC calendar and marks it
+//! valid in a backup register. At boot `init` checks the mark and, if set,
+//! anchors the `time` module to the RTC so log timestamps resume from real
+//! time instead of zero. The RTC calendar only covers 1970 to 2069.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use stm32l4xx_hal::{
+    datetime::{Date, Time},
+    rtc::Rtc,
+};
+
+use crate::time;
+
+// RTC shared with `store_unix_time`, which may be called from anywhere
+static RTC: Mutex<RefCell<Option<Rtc>>> = Mutex::new(RefCell::new(None));
+
+/// Backup register holding `VALID_MAGIC` once the calendar holds Unix time
+const VALID_REGISTER: usize = 0;
+
+/// Marker written to `VALID_REGISTER` ("NTP1")
+const VALID_MAGIC: u32 = 0x4E54_5031;
+
+const SECS_PER_DAY: u32 = 86_400;
+
+/// Take ownership of the RTC and restore the time it kept across the reset
+///
+/// Returns the restored Unix time in seconds, or `None` if the RTC has never
+/// been set by `store_unix_time`.
+pub fn init(rtc: Rtc) -> Option<u32> {
+    let restored = if rtc.read_backup_register(VALID_REGISTER) == Some(VALID_MAGIC) {
+        let (date, time) = rtc.get_date_time();
+        let unix_secs = to_unix(&date, &time);
+        time::set_unix_time(unix_secs);
+        Some(unix_secs)
+    } else {
+        None
+    };
+
+    interrupt::free(|cs| RTC.borrow(cs).replace(Some(rtc)));
+    restored
+}
+
+/// Persist `unix_secs` (e.g. from `WifiModule::sync_time`) in the RTC
+pub fn store_unix_time(unix_secs: u32) {
+    interrupt::free(|cs| {
+        if let Some(rtc) = RTC.borrow(cs).borrow_mut().as_mut() {
+            let (date, time) = from_unix(unix_secs);
+            rtc.set_date_time(date, time);
+            rtc.write_backup_register(VALID_REGISTER, VALID_MAGIC);
+        }
+    });
+}
+
+/// Seconds since the Unix epoch for an RTC calendar reading
+fn to_unix(date: &Date, time: &Time) -> u32 {
+    let days = days_from_civil(date.year, date.month, date.date);
+    days * SECS_PER_DAY + time.hours * 3600 + time.minutes * 60 + time.seconds
+}
+
+/// RTC calendar reading for `unix_secs`
+fn from_unix(unix_secs: u32) -> (Date, Time) {
+    let days = unix_secs / SECS_PER_DAY;
+    let secs = unix_secs % SECS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+
+    let date = Date {
+        // 1970-01-01 was a Thursday; the RTC numbers Monday as 1
+        day: (days + 3) % 7 + 1,
+        date: day,
+        month,
+        year,
+    };
+    let time = Time {
+        hours: secs / 3600,
+        minutes: secs % 3600 / 60,
+        seconds: secs % 60,
+        micros: 0,
+        daylight_savings: false,
+    };
+    (date, time)
+}
+
+/// Days from 1970-01-01 to the given date (Howard Hinnant's algorithm)
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date (year, month, day) that is `days` after 1970-01-01
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u32::from(month <= 2);
+    (year, month, day)
+}
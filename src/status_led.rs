@@ -0,0 +1,79 @@
+//! Onboard LED patterns that show the WiFi connection status
+//!
+//! `StatusLed` owns the LED pin and derives its level from the millisecond
+//! timebase, so `tick` can be called at any rate from the main loop; it only
+//! needs to run often enough for the fast blink to be visible (every 50ms
+//! or so).
+
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::time::now_ms;
+use crate::wifi::WifiState;
+
+/// Half-period of the slow blink shown while connecting
+const SLOW_BLINK_MS: u32 = 500;
+
+/// Half-period of the fast blink shown after an error
+const FAST_BLINK_MS: u32 = 100;
+
+/// What the LED is showing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkStatus {
+    /// Not connected to any network: off
+    Disconnected,
+    /// A connection attempt is in progress: slow blink
+    Connecting,
+    /// Joined a network or hosting an access point: solid
+    Connected,
+    /// The last connection attempt failed: fast blink
+    Error,
+}
+
+impl From<WifiState> for LinkStatus {
+    fn from(state: WifiState) -> Self {
+        match state {
+            WifiState::Disconnected => LinkStatus::Disconnected,
+            WifiState::Connected | WifiState::AccessPoint => LinkStatus::Connected,
+        }
+    }
+}
+
+/// LED driven according to a `LinkStatus`
+pub struct StatusLed<PIN> {
+    pin: PIN,
+    status: LinkStatus,
+}
+
+impl<PIN: OutputPin> StatusLed<PIN> {
+    /// Take over `pin`, starting out `Disconnected` (off)
+    pub fn new(pin: PIN) -> Self {
+        let mut led = Self {
+            pin,
+            status: LinkStatus::Disconnected,
+        };
+        led.tick();
+        led
+    }
+
+    /// Show `status`, e.g. `WifiModule::state()`, from the next `tick`
+    pub fn set_status(&mut self, status: impl Into<LinkStatus>) {
+        self.status = status.into();
+    }
+
+    /// Drive the LED to the level the current pattern calls for right now
+    pub fn tick(&mut self) {
+        let on = match self.status {
+            LinkStatus::Disconnected => false,
+            LinkStatus::Connecting => (now_ms() / SLOW_BLINK_MS).is_multiple_of(2),
+            LinkStatus::Connected => true,
+            LinkStatus::Error => (now_ms() / FAST_BLINK_MS).is_multiple_of(2),
+        };
+        // The LED is only an indicator, so a pin that can't be driven is ignored
+        let _ = if on {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        };
+    }
+}
@@ -0,0 +1,117 @@
+//! Millisecond timebase driven by the TIM2 interrupt
+//!
+//! The counter is a `u32` and wraps after ~49.7 days. Timeouts must therefore
+//! never compare raw timestamps; measure intervals with `elapsed_ms`, which
+//! uses wrapping subtraction and stays correct across the wrap as long as the
+//! interval itself is shorter than the full counter period.
+//!
+//! Once the network time is known, `set_unix_time` anchors the counter to the
+//! Unix epoch so `unix_time_ms` (and the log timestamps) report wall-clock
+//! time. The anchor is only exact until the counter first wraps.
+//!
+//! For profiling, `micros` reads the DWT cycle counter once `enable_micros`
+//! has started it.
+//!
+//! `now_ms` is the only way to read the counter. It is a single atomic load,
+//! so it never returns a torn value, even while TIM2 interrupts it. `Relaxed`
+//! ordering is enough: the counter does not publish any other data, and on
+//! this single-core part every access still happens in program order.
+//! Anything that combines the counter with other state, such as the epoch
+//! anchor below, reads both inside a critical section, so the ISR cannot
+//! tick between the two reads. Callers that take one `now_ms` and compare
+//! against it later, as every timeout does, need no locking.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::{
+    interrupt,
+    peripheral::{DCB, DWT},
+};
+
+// Global timestamp counter (milliseconds since boot)
+static TIMESTAMP_MS: AtomicU32 = AtomicU32::new(0);
+
+// Unix time in seconds at which the counter read zero (0 until synced)
+static EPOCH_OFFSET: AtomicU32 = AtomicU32::new(0);
+
+// Core clock cycles per microsecond (0 until `enable_micros` is called)
+static CYCLES_PER_US: AtomicU32 = AtomicU32::new(0);
+
+/// Advance the timebase by one millisecond; called from the TIM2 interrupt
+pub fn tick() {
+    // Atomic adds wrap on overflow, which `elapsed_ms` relies on
+    TIMESTAMP_MS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Milliseconds since boot, wrapping at `u32::MAX`
+pub fn now_ms() -> u32 {
+    TIMESTAMP_MS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds elapsed since the timestamp `since`, correct across wraparound
+pub fn elapsed_ms(since: u32) -> u32 {
+    elapsed_between(since, now_ms())
+}
+
+/// Milliseconds from timestamp `start` to timestamp `now`, correct across
+/// wraparound
+///
+/// For example, `start = u32::MAX - 5` with `now = 4` gives `10`.
+pub fn elapsed_between(start: u32, now: u32) -> u32 {
+    now.wrapping_sub(start)
+}
+
+/// Start the DWT cycle counter behind `micros`, given the core clock in Hz
+pub fn enable_micros(dcb: &mut DCB, dwt: &mut DWT, sysclk_hz: u32) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    CYCLES_PER_US.store((sysclk_hz / 1_000_000).max(1), Ordering::Relaxed);
+}
+
+/// Microseconds from the DWT cycle counter, or 0 before `enable_micros`
+///
+/// This wraps along with the cycle counter, every 2^32 cycles (about 18
+/// minutes at the default 4MHz), rather than at `u32::MAX`. So it is only
+/// for timing short spans with `elapsed_us`; use `now_ms` for timeouts.
+/// The counter may also stop while the core sleeps in `WFI`, so spans that
+/// wait on an interrupt can read short.
+pub fn micros() -> u32 {
+    match CYCLES_PER_US.load(Ordering::Relaxed) {
+        0 => 0,
+        cycles_per_us => DWT::cycle_count() / cycles_per_us,
+    }
+}
+
+/// Microseconds elapsed since the `micros` timestamp `since`
+///
+/// A span across the cycle counter wrap reads wrong, which is acceptable
+/// for the occasional profiling sample.
+pub fn elapsed_us(since: u32) -> u32 {
+    micros().wrapping_sub(since)
+}
+
+/// Anchor the timebase to wall-clock time, given the current Unix time in seconds
+pub fn set_unix_time(unix_secs: u32) {
+    interrupt::free(|_| {
+        EPOCH_OFFSET.store(unix_secs.wrapping_sub(now_ms() / 1000), Ordering::Relaxed);
+    });
+}
+
+/// Milliseconds since the Unix epoch, or since boot if the time was never set
+#[cfg_attr(not(feature = "defmt"), allow(dead_code))] // Only the log timestamp reads it
+pub fn unix_time_ms() -> u64 {
+    let (offset, ms) = interrupt::free(|_| (EPOCH_OFFSET.load(Ordering::Relaxed), now_ms()));
+    u64::from(offset) * 1000 + u64::from(ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed_across_wrap() {
+        assert_eq!(elapsed_between(u32::MAX - 5, 4), 10);
+        assert_eq!(elapsed_between(100, 100), 0);
+        assert_eq!(elapsed_between(0, u32::MAX), u32::MAX);
+    }
+}
@@ -6,418 +6,4005 @@
 //!
 //! The implementation is based on the es-wifi-driver reference implementation
 //! and provides basic WiFi connectivity functionality.
+//!
+//! The driver uses the embedded-hal 0.2 blocking `Transfer` trait and drives
+//! chip select itself. It is not built on embedded-hal 1.0 `SpiDevice`: the
+//! HAL only implements 0.2, and the eS-WiFi read path keeps CS asserted while
+//! polling the data-ready pin between words, which a `SpiDevice` transaction
+//! cannot express without releasing CS.
+//!
+//! The SPI bus is built by the caller, so its clock is chosen outside the
+//! driver; see `RECOMMENDED_SPI_HZ` and `MAX_SPI_HZ`. The framing does not
+//! depend on the clock: every word is a full-duplex 16-bit transfer and the
+//! module paces the exchange with the data-ready pin, never with bit timing.
 
-use cortex_m::asm::nop;
-use defmt::{debug, error, info, warn};
-use embedded_hal::blocking::{delay::DelayMs, spi::Transfer};
-use heapless::String;
+// The driver exposes more API than the demo in `main.rs` exercises
+#![allow(dead_code)]
 
-use stm32l4xx_hal::{
-    gpio::{gpiob::*, gpioc::*, gpioe::*, Alternate, Input, Output, PullUp, PushPull},
-    pac::SPI3,
-    spi::Spi,
+use core::{
+    cell::Cell,
+    fmt::Write,
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, Ordering},
 };
+#[cfg(not(test))]
+use cortex_m::asm::wfi;
+use embedded_hal::{
+    blocking::{
+        delay::{DelayMs, DelayUs},
+        spi::Transfer,
+    },
+    digital::v2::{InputPin, OutputPin},
+    spi::{Mode, MODE_0},
+};
+use heapless::{String, Vec};
+
+use crate::logging::{debug, info, warn};
+use crate::time::{elapsed_ms, elapsed_us, micros, now_ms, set_unix_time};
 
-// WiFi module pins on STM32L475 Discovery board
-// SPI3_SCK  -> PC10 (connected to ISM43362 SPI_CLK)
-// SPI3_MOSI -> PC12 (connected to ISM43362 SPI_MOSI)
-// SPI3_MISO -> PC11 (connected to ISM43362 SPI_MISO)
-// WiFi_CS   -> PE0  (Chip Select)
-// WiFi_RST  -> PE8  (Reset)
-// WiFi_WKUP -> PB13 (Wake up)
-
-/// SPI peripheral type for WiFi communication
-pub type WifiSpi = Spi<
-    SPI3,
-    (
-        PC10<Alternate<PushPull, 6>>, // SCK
-        PC11<Alternate<PushPull, 6>>, // MISO
-        PC12<Alternate<PushPull, 6>>, // MOSI
-    ),
->;
+#[cfg(feature = "board-b-l475e-iot01a")]
+pub mod discovery;
+#[cfg(feature = "board-b-l475e-iot01a")]
+pub mod dma;
+pub mod mqtt;
+pub mod nal;
+
+#[cfg(feature = "board-b-l475e-iot01a")]
+pub use discovery::{DiscoveryPorts, DiscoveryWifiModule, WifiSpi};
 
 /// GPIO pins used for WiFi module control
-pub struct WifiPins {
+///
+/// `discovery::DiscoveryWifiPins` names the STM32L475 Discovery board wiring
+/// given in brackets below.
+pub struct WifiPins<CS, RST, WAKE, RDY> {
     /// Chip Select pin (PE0)
-    pub cs: PE0<Output<PushPull>>,
+    pub cs: CS,
     /// Reset pin (PE8)
-    pub reset: PE8<Output<PushPull>>,
+    pub reset: RST,
     /// Wake-up pin (PB13)
-    pub wakeup: PB13<Output<PushPull>>,
+    pub wakeup: WAKE,
     /// Data Ready pin (PE1) - indicates when module is ready for communication
-    pub data_ready: PE1<Input<PullUp>>,
+    pub data_ready: RDY,
 }
 
 /// WiFi connection states
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WifiState {
     /// Module is disconnected from any network
     Disconnected = 0,
     /// Module is connected to a WiFi network
     Connected = 1,
+    /// Module is hosting its own network with `start_access_point`
+    AccessPoint = 2,
 }
 
-/// Main WiFi module driver structure
+/// The host test build has no interrupts to sleep until, nor the `WFI`
+/// instruction, so waits just poll
+#[cfg(test)]
+fn wfi() {}
+
+/// Set by the data-ready EXTI interrupt, cleared when a wait consumes it
+static DATA_READY_EVENT: AtomicBool = AtomicBool::new(false);
+
+/// Record a rising edge on the data-ready line
 ///
-/// This structure encapsulates the SPI peripheral, GPIO pins, and state
-/// needed to communicate with the ISM43362 WiFi module using the eS-WiFi protocol.
-pub struct WifiModule {
-    /// SPI peripheral for communication
-    pub spi: WifiSpi,
-    /// GPIO pins for module control
-    pub pins: WifiPins,
-    /// Current connection state
-    state: WifiState,
+/// Call this from the EXTI interrupt handler for the data-ready pin (EXTI1
+/// for PE1 on the Discovery board) after clearing its pending bit.
+///
+/// The edge only ever answers a command the host sent: the eS-WiFi SPI
+/// firmware never raises data-ready on its own when socket data arrives, so
+/// it cannot signal "socket has data". Servers still have to poll with
+/// `tcp_receive`, which returns 0 when nothing is pending.
+pub fn notify_data_ready() {
+    DATA_READY_EVENT.store(true, Ordering::Release);
 }
 
-impl WifiModule {
-    pub fn new(spi: WifiSpi, pins: WifiPins) -> Self {
-        Self {
-            spi,
-            pins,
-            state: WifiState::Disconnected,
-        }
-    }
+/// Discard any data-ready event recorded before a new transaction starts
+pub fn clear_data_ready_event() {
+    DATA_READY_EVENT.store(false, Ordering::Release);
+}
 
-    pub fn init(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), &'static str> {
-        info!("Starting WiFi module reset sequence...");
+/// Default for the `RX` buffer size of `WifiModule`
+pub const DEFAULT_RX_BUFFER_SIZE: usize = 256;
 
-        // Reset the WiFi module (as per es-wifi-driver timing)
-        self.pins.reset.set_low();
-        delay.delay_ms(50);
-        self.pins.reset.set_high();
-        delay.delay_ms(50);
-        info!("WiFi module reset completed");
+/// How long to wait for the module to assert data-ready before giving up
+pub const RESPONSE_TIMEOUT_MS: u32 = 10_000;
 
-        // Wake up the module (as per es-wifi-driver timing)
-        self.pins.wakeup.set_high();
-        delay.delay_ms(50);
-        info!("WiFi module wake-up signal sent");
+/// SPI clock the driver is exercised at on the Discovery board
+///
+/// Slow enough for jumper-wired modules, and reachable from the default
+/// 4MHz MSI clock (SPI3 runs at most at PCLK1 / 2).
+pub const RECOMMENDED_SPI_HZ: u32 = 1_000_000;
 
-        // Fetch initial cursor as required by ISM43362 spec
-        info!("Fetching initial cursor...");
-        match self.fetch_initial_cursor(delay) {
-            Ok(cursor) => info!("Successfully fetched initial cursor: '{}'", cursor.as_str()),
-            Err(e) => warn!("Failed to fetch initial cursor: {}", e),
-        }
+/// Fastest SPI clock the ISM43362 datasheet allows
+///
+/// Anything from a few hundred kHz up to this is safe; going faster than
+/// `RECOMMENDED_SPI_HZ` needs a system clock that gives PCLK1 >= 2x the rate.
+pub const MAX_SPI_HZ: u32 = 20_000_000;
 
-        // Disable verbosity as per es-wifi-driver
-        info!("Disabling verbosity...");
-        let _response = self.send_at_command("MT=1\r")?;
+/// Time chip select is held after each edge before clocking or reselecting,
+/// as per es-wifi-driver
+pub const CS_HOLD_US: u32 = 15;
 
-        // Test basic communication using eS-WiFi commands
-        info!("Testing basic eS-WiFi communication...");
-        let version_response = self.send_at_command("MR\r")?; // Get module version
-        info!("Module version info: {}", version_response.as_str());
+/// How long data-ready may stay low between two chunks of one response
+///
+/// The module can drop the line briefly while it refills its SPI buffer;
+/// only a drop that outlasts this window ends the response.
+pub const CONTINUATION_WINDOW_US: u32 = 50;
 
-        info!("WiFi module initialization completed successfully");
-        Ok(())
+/// Interval at which data-ready is re-checked during that window
+const CONTINUATION_POLL_US: u32 = 5;
+
+/// All-NAK words at the start of a reply after which the module is `Busy`
+pub const BUSY_NAK_WORDS: usize = 128;
+
+/// Default for `WifiModule::spi_retries`
+pub const DEFAULT_SPI_RETRIES: u8 = 2;
+
+/// Default for `WifiModule::data_ready_samples`: trust a single read
+pub const DEFAULT_DATA_READY_SAMPLES: u8 = 1;
+
+/// Time the module needs after the wake-up line rises before taking commands
+pub const WAKEUP_LATENCY_MS: u32 = 50;
+
+/// Deadline for a whole command transaction in `send_at_command`
+pub const COMMAND_TIMEOUT_MS: u32 = 10_000;
+
+/// How long `init` waits for the module to present its initial cursor
+pub const INITIAL_CURSOR_TIMEOUT_MS: u32 = 10_000;
+
+/// How long `recover` waits for the prompt after resynchronising
+pub const RECOVERY_TIMEOUT_MS: u32 = 1_000;
+
+/// Most words `recover` drains from an interrupted reply
+const RECOVERY_FLUSH_WORDS: usize = 2048;
+
+/// Default time allowed for the module to obtain an IP address after `C0`
+pub const CONNECT_TIMEOUT_MS: u32 = 10_000;
+
+/// Default interval between `C?` status checks while waiting for an IP address
+pub const STATUS_POLL_INTERVAL_MS: u32 = 500;
+
+/// Default number of times the module retries joining before `C0` fails
+pub const JOIN_RETRIES: u8 = 3;
+
+/// Longest SSID 802.11 allows, in bytes
+pub const SSID_MAX_LEN: usize = 32;
+
+/// Longest WPA passphrase, in bytes
+pub const PASSWORD_MAX_LEN: usize = 63;
+
+/// Longest hostname the module accepts with `ZN=`
+pub const HOSTNAME_MAX_LEN: usize = 32;
+
+/// Highest transmit power index accepted by `CP=`
+pub const TX_POWER_MAX: u8 = 3;
+
+/// Delay between `poll_connect` calls in the blocking `connect_to_network`
+const CONNECT_POLL_DELAY_MS: u32 = 10;
+
+/// Default number of connection attempts made by `reconnect`
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// Delay before the second `reconnect` attempt; doubles after each failure
+pub const RECONNECT_INITIAL_BACKOFF_MS: u32 = 500;
+
+/// Upper bound on the delay between `reconnect` attempts
+pub const RECONNECT_MAX_BACKOFF_MS: u32 = 30_000;
+
+/// Network settings, in the order the `C?` status reply lists them
+///
+/// Each setting's value is its position in that reply, and the command
+/// that sets it is `C` followed by the position as a hex digit: the eS-WiFi
+/// AT command set numbers them `C1` (SSID) to `CC` (auto connect) in the
+/// same order. `parse_connection_status` and the setters both go through
+/// this table, so they cannot disagree on the numbering.
+#[derive(Debug, Clone, Copy)]
+enum NetworkSetting {
+    Ssid = 1,
+    Password,
+    /// Encryption type, see `SecurityMode::encryption_code`
+    Security,
+    /// 1 to obtain the address by DHCP, 0 to use the static settings
+    Dhcp,
+    IpVersion,
+    IpAddress,
+    Netmask,
+    Gateway,
+    PrimaryDns,
+    SecondaryDns,
+    /// Times the module retries joining before giving up
+    JoinRetries,
+    AutoConnect,
+}
+
+/// Fields the `C?` reply has after the last `NetworkSetting`: the
+/// authentication type, country and connection status
+const STATUS_TRAILING_FIELDS: usize = 3;
+
+impl NetworkSetting {
+    /// Append the command setting this field to `value`, e.g. `C4=0\r`
+    fn write_command<const N: usize>(
+        self,
+        cmd: &mut String<N>,
+        value: impl core::fmt::Display,
+    ) -> Result<(), WifiError> {
+        write!(cmd, "C{:X}={}\r", self as u8, value).map_err(|_| WifiError::BufferFull)
     }
+}
 
-    pub fn check_data_ready_pin(&self) -> bool {
-        // According to ISM43362 spec: CMD/DATA READY pin HIGH = data ready
-        self.pins.data_ready.is_high()
+/// Steps of the non-blocking connect state machine driven by `poll_connect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectState {
+    /// No connection attempt in progress
+    Idle,
+    /// Leave any existing network (`CD`)
+    Disconnect,
+    /// Send the SSID (`C1=`)
+    SetSsid,
+    /// Send the password (`C2=`); skipped for open networks
+    SetPassword,
+    /// Select the encryption type (`C3=`)
+    SetEncryption,
+    /// Set how often the module retries joining (`CB=`)
+    SetJoinRetries,
+    /// Issue the connect command (`C0`)
+    Join,
+    /// Poll the status (`C?`) until an IP address is assigned
+    WaitForIp { started_ms: u32, last_poll_ms: u32 },
+}
+
+/// Callback the driver's blocking loops use to keep a hardware watchdog fed
+pub type WatchdogFeed = fn();
+
+/// Longest stretch the driver sleeps without feeding the watchdog
+const WATCHDOG_FEED_INTERVAL_MS: u32 = 100;
+
+/// Transport protocol for a module-managed socket (`P1=`)
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// TCP client connection
+    Tcp = 0,
+    /// UDP datagrams
+    Udp = 1,
+    /// TCP wrapped in the module's built-in TLS (TCP-SSL in the eS-WiFi spec)
+    Tls = 3,
+}
+
+/// Number of socket slots the module provides (`P0=0` to `P0=3`)
+pub const MAX_SOCKETS: usize = 4;
+
+/// Handle to one of the module's socket slots
+///
+/// Returned by `open_socket`/`udp_open`/`listen` and consumed by
+/// `close_socket`, which frees the slot for reuse.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Socket {
+    index: u8,
+    /// Started as a server with `P5=1` rather than a client with `P6=1`
+    listening: bool,
+}
+
+impl Socket {
+    /// Slot number selected with `P0=`
+    pub fn index(&self) -> u8 {
+        self.index
     }
+}
 
-    /// Fetch initial cursor after power-up/reset
-    pub fn fetch_initial_cursor(
-        &mut self,
-        delay: &mut impl DelayMs<u32>,
-    ) -> Result<String<64>, &'static str> {
-        info!("Fetching initial cursor...");
+/// WiFi security mode of an access point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityMode {
+    /// No authentication
+    Open,
+    /// Legacy WEP
+    Wep,
+    /// WPA personal
+    WpaPsk,
+    /// WPA2 personal
+    Wpa2Psk,
+    /// WPA2 with 802.1X authentication; seen in scans but cannot be joined
+    ///
+    /// The ISM43362 firmware has no commands for an EAP method, identity or
+    /// client certificate (`PG=` only loads TLS socket certificates), so
+    /// `connect` and `start_access_point` reject this mode.
+    Wpa2Enterprise,
+    /// WPA3 personal
+    Wpa3Psk,
+}
 
-        // Wait for CMD/DATA READY pin to go HIGH (data ready)
-        let mut timeout = 0;
-        while !self.check_data_ready_pin() && timeout < 1000 {
-            delay.delay_ms(10);
-            timeout += 1;
+impl SecurityMode {
+    /// Parse the security field of an `F0` scan line (e.g. `WPA2 AES`)
+    ///
+    /// Mixed modes such as `WPA WPA2` report the strongest mode offered.
+    fn from_scan_field(field: &str) -> Option<Self> {
+        if field.contains("WPA3") {
+            Some(SecurityMode::Wpa3Psk)
+        } else if field.contains("WPA2") && (field.contains("Enterprise") || field.contains("EAP"))
+        {
+            Some(SecurityMode::Wpa2Enterprise)
+        } else if field.contains("WPA2") {
+            Some(SecurityMode::Wpa2Psk)
+        } else if field.contains("WPA") {
+            Some(SecurityMode::WpaPsk)
+        } else if field.contains("WEP") {
+            Some(SecurityMode::Wep)
+        } else if field.contains("Open") {
+            Some(SecurityMode::Open)
+        } else {
+            None
         }
+    }
 
-        if timeout >= 1000 {
-            return Err("Timeout waiting for initial data ready");
+    /// Value for the `C3=`/`A1=` encryption type commands
+    ///
+    /// WPA2 uses the mixed WPA/WPA2 type (4), as es-wifi-driver does. There
+    /// is no code for WPA2-Enterprise, which the module can't authenticate.
+    pub fn encryption_code(self) -> Option<u8> {
+        match self {
+            SecurityMode::Open => Some(0),
+            SecurityMode::Wep => Some(1),
+            SecurityMode::WpaPsk => Some(2),
+            SecurityMode::Wpa2Psk => Some(4),
+            SecurityMode::Wpa2Enterprise => None,
+            SecurityMode::Wpa3Psk => Some(5),
         }
+    }
 
-        info!("Data ready pin is HIGH, fetching cursor...");
+    /// Mode for an encryption type code, the inverse of `encryption_code`
+    ///
+    /// Both the WPA2-only (3) and mixed WPA/WPA2 (4) types map to `Wpa2Psk`.
+    pub fn from_encryption_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(SecurityMode::Open),
+            1 => Some(SecurityMode::Wep),
+            2 => Some(SecurityMode::WpaPsk),
+            3 | 4 => Some(SecurityMode::Wpa2Psk),
+            5 => Some(SecurityMode::Wpa3Psk),
+            _ => None,
+        }
+    }
+}
 
-        // Select the WiFi module
-        self.pins.cs.set_low();
-        delay.delay_ms(1);
+/// WiFi frequency band, for `set_band`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Band {
+    /// 2.4GHz, channels 1-13
+    TwoPointFour,
+    /// 5GHz
+    Five,
+}
 
-        let mut cursor = String::<64>::new();
+/// Parameters for `connect`, built up from `ConnectConfig::new`
+///
+/// ```ignore
+/// let config = ConnectConfig::new("Subway")
+///     .password("5$FootLong")
+///     .security(SecurityMode::Wpa2Psk)
+///     .timeout_ms(15_000)
+///     .poll_interval_ms(250);
+/// wifi.connect(&config, &mut delay)?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectConfig<'a> {
+    ssid: &'a str,
+    password: &'a str,
+    security: SecurityMode,
+    hidden: bool,
+    join_retries: u8,
+    timeout_ms: u32,
+    poll_interval_ms: u32,
+}
 
-        // Clock out 0x0A (Line Feed) until CMD/DATA READY pin goes LOW
-        // Using 8-bit transfers but following 16-bit protocol (send MSB first, then LSB)
-        while self.check_data_ready_pin() {
-            // Send 16-bit word as two 8-bit transfers: MSB first, then LSB
-            let mut tx_msb = [0x0A]; // MSB: Line Feed
-            let rx_msb = self
-                .spi
-                .transfer(&mut tx_msb)
-                .map_err(|_| "SPI transfer failed")?;
-
-            let mut tx_lsb = [0x00]; // LSB: 0x00
-            let rx_lsb = self
-                .spi
-                .transfer(&mut tx_lsb)
-                .map_err(|_| "SPI transfer failed")?;
-
-            // Store received data from both bytes
-            for &received_byte in &[rx_msb[0], rx_lsb[0]] {
-                if received_byte >= 32 && received_byte <= 126 {
-                    cursor
-                        .push(received_byte as char)
-                        .map_err(|_| "Cursor too long")?;
-                }
-            }
+impl<'a> ConnectConfig<'a> {
+    /// Join `ssid` as an open network with the default `JOIN_RETRIES`,
+    /// `CONNECT_TIMEOUT_MS` and `STATUS_POLL_INTERVAL_MS`
+    pub fn new(ssid: &'a str) -> Self {
+        Self {
+            ssid,
+            password: "",
+            security: SecurityMode::Open,
+            hidden: false,
+            join_retries: JOIN_RETRIES,
+            timeout_ms: CONNECT_TIMEOUT_MS,
+            poll_interval_ms: STATUS_POLL_INTERVAL_MS,
         }
+    }
 
-        // Deselect the WiFi module
-        self.pins.cs.set_high();
-        delay.delay_ms(1);
+    /// Network password; required by every mode except `SecurityMode::Open`
+    pub fn password(mut self, password: &'a str) -> Self {
+        self.password = password;
+        self
+    }
 
-        info!("Received cursor: '{}'", cursor.as_str());
-        Ok(cursor)
+    /// Security mode of the network
+    pub fn security(mut self, security: SecurityMode) -> Self {
+        self.security = security;
+        self
     }
 
-    pub fn test_communication(&mut self) -> Result<(), &'static str> {
-        info!("Testing WiFi module communication...");
+    /// Whether the network hides its SSID and must be probed for actively
+    ///
+    /// The ISM43362 eS-WiFi AT command set has no option for this in any
+    /// firmware revision: the station settings are `C1` to `CC` as listed
+    /// by `C?`, and none of them asks for active probing (`C4`, tried here
+    /// before, is the DHCP switch). So `connect` fails with
+    /// `WifiError::Unsupported` when this is set, rather than silently
+    /// joining without it.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
 
-        // Check initial data ready pin state
-        let data_ready_initial = self.check_data_ready_pin();
-        info!(
-            "Initial data ready pin state: {}",
-            if data_ready_initial {
-                "READY"
-            } else {
-                "NOT READY"
-            }
-        );
+    /// Times the module retries joining the network before `C0` fails
+    pub fn join_retries(mut self, join_retries: u8) -> Self {
+        self.join_retries = join_retries;
+        self
+    }
 
-        // Send a simple eS-WiFi command to test communication
-        info!("Sending test eS-WiFi command...");
+    /// Time allowed for the module to obtain an IP address after joining
+    pub fn timeout_ms(mut self, timeout_ms: u32) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
 
-        // Get MAC address
-        let response = self.send_at_command("Z5\r")?;
-        info!("MAC address: {}", response.as_str());
+    /// Interval between `C?` status checks while waiting for an address
+    ///
+    /// Together with `timeout_ms` this sets how many checks are made; a
+    /// shorter interval notices a quick DHCP lease sooner.
+    pub fn poll_interval_ms(mut self, poll_interval_ms: u32) -> Self {
+        self.poll_interval_ms = poll_interval_ms;
+        self
+    }
+}
 
-        Ok(())
+/// Module identity reported by `get_module_info`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ModuleInfo {
+    /// Product identifier, e.g. `ISM43362-M3G-L44-SPI`
+    pub product_id: String<32>,
+    /// Firmware revision, e.g. `C3.5.2.5.STM`
+    pub firmware_version: String<32>,
+    /// eS-WiFi API revision, e.g. `v3.5.2`
+    pub api_version: String<16>,
+}
+
+/// An access point reported by `scan_networks`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ApInfo {
+    /// Network name
+    pub ssid: String<SSID_MAX_LEN>,
+    /// Received signal strength in dBm
+    pub rssi: i8,
+    /// 2.4GHz channel number
+    pub channel: u8,
+    /// Advertised security mode
+    pub security: SecurityMode,
+    /// MAC address of the access point
+    pub bssid: [u8; 6],
+}
+
+/// An IPv4 address as used by the eS-WiFi commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WifiIpv4([u8; 4]);
+
+impl WifiIpv4 {
+    /// The all-zero address the module reports when none is assigned
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
     }
 
-    /// Send command using 16-bit SPI transfers as per ISM43362 spec
-    fn send_command_16bit(&mut self, command: &str) -> Result<(), &'static str> {
-        info!("Sending 16-bit command: {}", command.trim());
-
-        // Select the WiFi module (as per es-wifi-driver timing)
-        self.pins.cs.set_low();
-
-        // Send command bytes using 16-bit protocol as per es-wifi-driver
-        let cmd_bytes: heapless::Vec<u8, 256> = command.bytes().collect();
-        for chunk in cmd_bytes.chunks(2) {
-            let mut xfer: [u8; 2] = [0; 2];
-            xfer[1] = chunk[0]; // LSB gets first byte
-            if chunk.len() == 2 {
-                xfer[0] = chunk[1]; // MSB gets second byte
-            } else {
-                xfer[0] = 0x0A; // MSB gets 0x0A if odd length
-            }
+    pub const fn octets(&self) -> [u8; 4] {
+        self.0
+    }
 
-            self.spi
-                .transfer(&mut xfer)
-                .map_err(|_| "SPI transfer failed")?;
-        }
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+}
 
-        // Deselect the WiFi module (minimal hold time as per es-wifi-driver)
-        self.pins.cs.set_high();
-        // No delay needed here - es-wifi-driver uses only 15 microseconds
+impl From<[u8; 4]> for WifiIpv4 {
+    fn from(octets: [u8; 4]) -> Self {
+        Self(octets)
+    }
+}
 
-        // Check data ready pin state after sending command
-        debug!(
-            "Data ready pin after command: {}",
-            if self.check_data_ready_pin() {
-                "HIGH"
-            } else {
-                "LOW"
-            }
-        );
+impl core::fmt::Display for WifiIpv4 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
 
-        Ok(())
+#[cfg(feature = "defmt")]
+impl defmt::Format for WifiIpv4 {
+    fn format(&self, f: defmt::Formatter) {
+        let [a, b, c, d] = self.0;
+        defmt::write!(f, "{}.{}.{}.{}", a, b, c, d)
     }
+}
 
-    /// Read response using 16-bit SPI transfers as per ISM43362 spec
-    fn read_response_16bit(&mut self) -> Result<String<256>, &'static str> {
-        // Wait for data ready signal
-        debug!("Waiting for data ready signal...");
-        while !self.check_data_ready_pin() {
-            nop();
-        }
+/// Results of a `ping`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingStats {
+    /// Echo requests sent
+    pub sent: u8,
+    /// Echo replies received
+    pub received: u8,
+    /// Fastest round trip in ms (0 if nothing was received)
+    pub min_ms: u32,
+    /// Mean round trip in ms (0 if nothing was received)
+    pub avg_ms: u32,
+    /// Slowest round trip in ms (0 if nothing was received)
+    pub max_ms: u32,
+}
 
-        info!("Data ready for response, reading...");
+/// Addresses in use on the current network, as reported by `C?`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IpConfig {
+    /// Local address, assigned by DHCP or `set_static_ip`
+    pub ip: WifiIpv4,
+    pub netmask: WifiIpv4,
+    pub gateway: WifiIpv4,
+    /// Primary and secondary DNS servers
+    pub dns1: WifiIpv4,
+    pub dns2: WifiIpv4,
+}
 
-        // Select the WiFi module
-        self.pins.cs.set_low();
-        let mut response = String::<256>::new();
-        // Clock out 0x0A (Line Feed) until CMD/DATA READY pin goes LOW
-        // Using 16-bit protocol as per es-wifi-driver
-        while self.check_data_ready_pin() {
-            let mut xfer: [u8; 2] = [0x0A, 0x0A]; // Send 0x0A in both bytes
-            self.spi
-                .transfer(&mut xfer)
-                .map_err(|_| "SPI transfer failed")?;
+/// HTTP server and path that `post_telemetry` sends to
+#[derive(Debug, Clone, Copy)]
+pub struct HttpEndpoint<'a> {
+    /// Hostname, resolved with `resolve_hostname` for every request
+    pub host: &'a str,
+    pub port: u16,
+    /// Request path, e.g. `/api/telemetry`
+    pub path: &'a str,
+}
 
-            // Store received data, checking for NAK (0x15)
-            const NAK: u8 = 0x15;
+/// Network the module is configured to join, as reported by `C?`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StoredNetwork {
+    /// SSID set with `C1=`; empty if none is configured
+    pub ssid: String<SSID_MAX_LEN>,
+    /// Mode set with `C3=`, or `None` for a code the driver doesn't know
+    pub security: Option<SecurityMode>,
+    /// Whether the module joins this network on its own after a reset
+    pub auto_connect: bool,
+}
 
-            // Process in reverse order as per es-wifi-driver (16 -> 2*8 bits)
-            if xfer[1] != NAK {
-                response
-                    .push(xfer[1] as char)
-                    .map_err(|_| "Response too long")?;
-            }
-            if xfer[0] != NAK {
-                response
-                    .push(xfer[0] as char)
-                    .map_err(|_| "Response too long")?;
+/// Raw buffer for a `T0` ping response (one short line per echo)
+///
+/// Sized for the echoes `ping` asks for rather than by `RX`.
+const PING_BUFFER_SIZE: usize = 1024;
+
+/// Echo requests `verify_connectivity` sends to the gateway
+pub const VERIFY_PING_COUNT: u8 = 3;
+
+/// Maximum number of access points returned by `scan_networks`
+pub const MAX_SCAN_RESULTS: usize = 16;
+
+/// Longest single `F0` scan line `scan_networks_iter` buffers
+///
+/// Independent of `RX`: a line holds one AP, whose fields are bounded by
+/// `SSID_MAX_LEN` rather than the length of the whole reply.
+const SCAN_LINE_SIZE: usize = 160;
+
+/// Bytes clocked per SPI transfer on the bulk `S3`/`R0`/`F0` data paths
+///
+/// With `dma::DmaSpi` each such block is moved by DMA.
+pub const SPI_BULK_SIZE: usize = 64;
+
+/// Largest payload moved in a single `S3`/`R0` socket transaction
+const SOCKET_CHUNK_SIZE: usize = 512;
+
+/// Raw receive buffer: socket payload plus the surrounding eS-WiFi framing
+///
+/// Follows `SOCKET_CHUNK_SIZE`, the most a read asks for, not `RX`.
+const SOCKET_RX_BUFFER_SIZE: usize = SOCKET_CHUNK_SIZE + 16;
+
+/// UDP port NTP servers listen on
+const NTP_PORT: u16 = 123;
+
+/// Local UDP port used for NTP queries
+const NTP_LOCAL_PORT: u16 = 50_123;
+
+/// Size of an NTP packet without extension fields
+const NTP_PACKET_SIZE: usize = 48;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u32 = 2_208_988_800;
+
+/// How long `sync_time` waits for the server's reply
+pub const NTP_TIMEOUT_MS: u32 = 5_000;
+
+/// Longest request header `http_get`/`post_telemetry` can build
+const HTTP_REQUEST_SIZE: usize = 256;
+
+/// How long `http_get` waits for the first byte of the response
+pub const HTTP_TIMEOUT_MS: u32 = 10_000;
+
+/// Bytes of the reply `post_telemetry` reads to find the status code
+const HTTP_STATUS_BUFFER_SIZE: usize = 128;
+
+/// NAK byte the module clocks out as padding when it has nothing to send
+const NAK: u8 = 0x15;
+
+/// Commands that carry a secret, with the form they are logged in
+const REDACTED_COMMANDS: [(&str, &str); 2] = [("C2=", "C2=<redacted>"), ("A2=", "A2=<redacted>")];
+
+/// Commands whose reply carries a secret (`C?` includes the password)
+const REDACTED_REPLIES: [&str; 1] = ["C?"];
+
+/// Logged, and returned in errors, in place of a secret
+const REDACTED: &str = "<redacted>";
+
+/// Errors that can occur while talking to the WiFi module
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WifiError {
+    /// An SPI transfer to or from the module failed
+    SpiTransfer,
+    /// The module did not respond within the allotted time
+    Timeout,
+    /// A command or response did not fit in its fixed-size buffer
+    BufferFull,
+    /// A control GPIO could not be driven or read
+    Pin,
+    /// The module rejected a command; carries the reply it sent back,
+    /// truncated to keep every `Result` small whatever `RX` is
+    CommandFailed(String<32>),
+    /// The module's response did not have the expected shape
+    MalformedResponse,
+    /// The operation requires an active network connection
+    NotConnected,
+    /// The module refused the TLS certificate passed to `open_tls_socket`
+    CertificateRejected,
+    /// A command parameter was missing or out of range, either reported by
+    /// the module or caught before anything was sent
+    InvalidParameter,
+    /// The module ran out of memory carrying out a command
+    OutOfMemory,
+    /// The module clocked out nothing but NAK padding, so it is still busy;
+    /// back off and retry, or `recover` if it persists
+    Busy,
+    /// The module's firmware has no command for the requested option
+    Unsupported,
+}
+
+impl WifiError {
+    /// Build a `CommandFailed` from the module's reply, truncating it to fit
+    fn command_failed(reply: &str) -> Self {
+        let mut code = String::new();
+        for c in reply.chars() {
+            if code.push(c).is_err() {
+                break;
             }
         }
+        WifiError::CommandFailed(code)
+    }
 
-        // Validation
-        let mut lines = response.lines();
-        let _empty_line = lines
-            .next()
-            .ok_or("No starting empty line in command response")?;
-        let first_line = lines.next().ok_or("No response data")?;
-        let reply = lines.next().ok_or("No response reply code")?;
+    /// Map a failure reply from the module onto the matching error
+    ///
+    /// Recognises the eS-WiFi messages for bad parameters (`Parameter Error`,
+    /// `Usage: ...`), a missing connection and exhausted memory; anything
+    /// else, such as a bare `ERROR` or `-1`, is kept as `CommandFailed`.
+    fn from_reply(reply: &str) -> Self {
+        let contains = |needle: &str| {
+            reply
+                .as_bytes()
+                .windows(needle.len())
+                .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+        };
 
-        if reply != "OK" {
-            warn!("Failed command: {}", reply);
-            return Err("Command failed");
+        if contains("parameter") || contains("usage") {
+            WifiError::InvalidParameter
+        } else if contains("not connected") {
+            WifiError::NotConnected
+        } else if contains("memory") {
+            WifiError::OutOfMemory
+        } else {
+            WifiError::command_failed(reply)
         }
+    }
+}
+
+/// Main WiFi module driver structure
+///
+/// This structure encapsulates the SPI peripheral, GPIO pins, and state
+/// needed to communicate with the ISM43362 WiFi module using the eS-WiFi protocol.
+/// It is generic over the SPI bus, control pins and a microsecond delay so
+/// the module can be wired to any peripheral; `DiscoveryWifiModule` names the
+/// on-board setup (with the `board-b-l475e-iot01a` feature).
+///
+/// `RX` bounds the text replies read back; raise it for long replies or
+/// lower it to save RAM on small nodes. It is the only limit on a reply's
+/// length or line count. The other buffers hold one bounded item each
+/// rather than a whole reply (an SSID, a scan line, a socket chunk), so
+/// they are sized by what they hold instead.
+pub struct WifiModule<SPI, CS, RST, WAKE, RDY, DELAY, const RX: usize = DEFAULT_RX_BUFFER_SIZE> {
+    /// SPI peripheral for communication
+    pub spi: SPI,
+    /// GPIO pins for module control
+    pub pins: WifiPins<CS, RST, WAKE, RDY>,
+    /// Times a failed SPI transfer is retried before the command is abandoned;
+    /// set to zero on clean wiring to fail fast
+    pub spi_retries: u8,
+    /// Consecutive data-ready reads that must agree before a level change is
+    /// believed; raise it on noisy wiring
+    pub data_ready_samples: u8,
+    /// Last data-ready level the samples agreed on
+    data_ready_level: Cell<bool>,
+    /// Microsecond delay for the chip-select timing
+    delay: DELAY,
+    /// Current connection state
+    state: WifiState,
+    /// Progress of a connection started with `begin_connect`
+    connect_state: ConnectState,
+    /// Network last joined (or being joined), reused by `reconnect`
+    last_ssid: String<SSID_MAX_LEN>,
+    last_password: String<PASSWORD_MAX_LEN>,
+    last_security: SecurityMode,
+    /// Join retries set by `SetJoinRetries`, from `ConnectConfig::join_retries`
+    connect_join_retries: u8,
+    /// Time `WaitForIp` allows for an address, from `ConnectConfig::timeout_ms`
+    connect_timeout_ms: u32,
+    /// Interval between `WaitForIp` status checks, from
+    /// `ConnectConfig::poll_interval_ms`
+    connect_poll_interval_ms: u32,
+    /// Connection attempts `reconnect` makes before giving up
+    reconnect_attempts: u32,
+    /// Address assigned with `set_static_ip`, if DHCP is disabled
+    static_ip: Option<WifiIpv4>,
+    /// Socket slots currently handed out, indexed by slot number
+    sockets_in_use: [bool; MAX_SOCKETS],
+    /// Called from long-running loops, set with `set_watchdog_feed`
+    watchdog_feed: Option<WatchdogFeed>,
+}
 
-        let data = String::<256>::try_from(first_line)
-            .map_err(|_| "Could not represent data as string")?;
+impl<SPI, CS, RST, WAKE, RDY, DELAY, const RX: usize> WifiModule<SPI, CS, RST, WAKE, RDY, DELAY, RX>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    RST: OutputPin,
+    WAKE: OutputPin,
+    RDY: InputPin,
+    DELAY: DelayUs<u32>,
+{
+    /// SPI mode the ISM43362 requires: clock idle low, data captured on the
+    /// first (rising) edge
+    ///
+    /// The driver can't inspect the bus it is handed, and any other mode
+    /// only shows up as garbled replies, so build the SPI with this.
+    pub const REQUIRED_SPI_MODE: Mode = MODE_0;
 
-        // Deselect the WiFi module
-        self.pins.cs.set_high();
-        Ok(data)
+    pub fn new(spi: SPI, pins: WifiPins<CS, RST, WAKE, RDY>, delay: DELAY) -> Self {
+        Self {
+            spi,
+            pins,
+            spi_retries: DEFAULT_SPI_RETRIES,
+            data_ready_samples: DEFAULT_DATA_READY_SAMPLES,
+            data_ready_level: Cell::new(false),
+            delay,
+            state: WifiState::Disconnected,
+            connect_state: ConnectState::Idle,
+            last_ssid: String::new(),
+            last_password: String::new(),
+            last_security: SecurityMode::Open,
+            connect_join_retries: JOIN_RETRIES,
+            connect_timeout_ms: CONNECT_TIMEOUT_MS,
+            connect_poll_interval_ms: STATUS_POLL_INTERVAL_MS,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            static_ip: None,
+            sockets_in_use: [false; MAX_SOCKETS],
+            watchdog_feed: None,
+        }
     }
 
-    pub fn connect_to_network(
-        &mut self,
-        ssid: &str,
-        password: &str,
-        delay: &mut impl DelayMs<u32>,
-    ) -> Result<(), &'static str> {
-        info!("Starting WiFi connection process...");
+    /// SPI clock to configure the bus with; see `RECOMMENDED_SPI_HZ`
+    pub const fn recommended_spi_hz() -> u32 {
+        RECOMMENDED_SPI_HZ
+    }
 
-        // Disconnect from any existing network using eS-WiFi command
-        info!("Disconnecting from any existing network...");
-        let _response = self.send_at_command("CD\r")?; // Disconnect command
-
-        // Set security mode to WPA2 (CB=2) as per es-wifi-driver
-        info!("Setting security mode to WPA2...");
-        let _response = self.send_at_command("CB=2\r")?; // WPA2 security mode
-
-        // Set SSID using eS-WiFi command
-        info!("Setting SSID: {}", ssid);
-        let mut ssid_cmd: String<128> = String::new();
-        ssid_cmd.push_str("C1=").map_err(|_| "Command too long")?;
-        ssid_cmd.push_str(ssid).map_err(|_| "SSID too long")?;
-        ssid_cmd.push_str("\r").map_err(|_| "Command too long")?;
-        let _response = self.send_at_command(ssid_cmd.as_str())?;
-
-        // Set password using eS-WiFi command
-        info!("Setting password...");
-        let mut pwd_cmd: String<128> = String::new();
-        pwd_cmd.push_str("C2=").map_err(|_| "Command too long")?;
-        pwd_cmd
-            .push_str(password)
-            .map_err(|_| "Password too long")?;
-        pwd_cmd.push_str("\r").map_err(|_| "Command too long")?;
-        let _response = self.send_at_command(pwd_cmd.as_str())?;
-
-        // Set encryption type (C3=4 for WPA2) as per es-wifi-driver
-        info!("Setting encryption type...");
-        let _response = self.send_at_command("C3=4\r")?; // WPA2 encryption
-
-        // Connect to WiFi network using eS-WiFi command
-        info!("Connecting to WiFi network: {}", ssid);
-        let _response = self.send_at_command("C0\r")?; // Connect command
-
-        // Check connection status in a loop with ~10 second timeout
-        info!("Waiting for WiFi connection...");
-        let mut connection_attempts = 0;
-        const MAX_CONNECTION_ATTEMPTS: u32 = 20; // 20 attempts * 500ms = 10 seconds
+    /// Have the driver call `feed` while it blocks on the module
+    ///
+    /// Waiting for responses and joining a network can take several seconds;
+    /// `feed` is called between iterations of those loops (at least every
+    /// `WATCHDOG_FEED_INTERVAL_MS` while sleeping) so an independent watchdog
+    /// does not reset the board mid-operation.
+    pub fn set_watchdog_feed(&mut self, feed: WatchdogFeed) {
+        self.watchdog_feed = Some(feed);
+    }
 
-        loop {
-            delay.delay_ms(500); // Wait 500ms between checks
-            connection_attempts += 1;
-
-            match self.send_at_command("C?\r") {
-                Ok(response) => {
-                    // Parse the response to check if connection was successful
-                    // Look for IP address pattern (xxx.xxx.xxx.xxx) which indicates successful connection
-                    if response.contains("192.168.")
-                        || response.contains("10.")
-                        || response.contains("172.")
-                    {
-                        info!("WiFi connection successful! Status: {}", response.as_str());
-                        self.state = WifiState::Connected;
-                        break;
-                    } else if response.contains("Failed") {
-                        warn!("WiFi connection failed: {}", response.as_str());
-                        return Err("WiFi connection failed");
-                    } else if response.len() > 0 {
-                        debug!(
-                            "Connection attempt {}/{}: {}",
-                            connection_attempts,
-                            MAX_CONNECTION_ATTEMPTS,
-                            response.as_str()
-                        );
-                    } else {
-                        debug!(
-                            "Connection attempt {}/{}: (empty response)",
-                            connection_attempts, MAX_CONNECTION_ATTEMPTS
-                        );
-                    }
-                }
-                Err(e) => {
-                    debug!(
-                        "Failed to check connection status (attempt {}): {}",
-                        connection_attempts, e
-                    );
-                }
-            }
+    /// Call the watchdog feed, if one is set
+    fn feed_watchdog(&self) {
+        if let Some(feed) = self.watchdog_feed {
+            feed();
+        }
+    }
 
-            if connection_attempts >= MAX_CONNECTION_ATTEMPTS {
-                warn!(
-                    "WiFi connection timeout after {} attempts",
-                    MAX_CONNECTION_ATTEMPTS
-                );
-                return Err("WiFi connection timeout");
-            }
+    /// Delay for `ms`, feeding the watchdog at least every `WATCHDOG_FEED_INTERVAL_MS`
+    fn delay_fed(&self, delay: &mut impl DelayMs<u32>, mut ms: u32) {
+        while ms > 0 {
+            self.feed_watchdog();
+            let step = ms.min(WATCHDOG_FEED_INTERVAL_MS);
+            delay.delay_ms(step);
+            ms -= step;
         }
+    }
 
-        info!("WiFi connection process completed");
-        Ok(())
+    /// Reset the module and bring it into command mode
+    ///
+    /// Fails as soon as the module does not present its ready prompt, since
+    /// every later command would fail too.
+    pub fn init(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        self.init_with(delay, false)
     }
 
-    fn send_at_command(&mut self, command: &str) -> Result<String<256>, &'static str> {
-        debug!("Sending AT command: {}", command.trim());
+    /// `init`, optionally pushing on when the initial cursor is not received
+    ///
+    /// With `force` a missing or malformed cursor is only logged, for
+    /// modules known to come up without a clean prompt.
+    pub fn init_with(
+        &mut self,
+        delay: &mut impl DelayMs<u32>,
+        force: bool,
+    ) -> Result<(), WifiError> {
+        self.hardware_reset(delay)?;
 
-        // Send the command using 16-bit protocol
-        self.send_command_16bit(command)?;
+        // Fetch initial cursor as required by ISM43362 spec; without the
+        // prompt the module did not come up and nothing else will work
+        info!("Fetching initial cursor...");
+        match self.fetch_initial_cursor(delay, INITIAL_CURSOR_TIMEOUT_MS) {
+            Ok(cursor) => info!("Successfully fetched initial cursor: '{}'", cursor.as_str()),
+            Err(e) if force => warn!("Failed to fetch initial cursor, continuing: {}", e),
+            Err(e) => {
+                warn!("Failed to fetch initial cursor: {}", e);
+                return Err(e);
+            }
+        }
 
-        // Read the response using 16-bit protocol
-        match self.read_response_16bit() {
+        // Disable verbosity as per es-wifi-driver
+        info!("Disabling verbosity...");
+        let _response = self.send_at_command("MT=1\r")?;
+
+        // Test basic communication using eS-WiFi commands
+        info!("Testing basic eS-WiFi communication...");
+        match self.get_module_info() {
+            Ok(module) => info!(
+                "Module {} firmware {} API {}",
+                module.product_id.as_str(),
+                module.firmware_version.as_str(),
+                module.api_version.as_str()
+            ),
+            Err(e) => warn!("Failed to read module version info: {}", e),
+        }
+
+        info!("WiFi module initialization completed successfully");
+        Ok(())
+    }
+
+    /// Pulse the reset line and raise wake-up, as per es-wifi-driver timing
+    ///
+    /// The module then boots and presents its command prompt, which the
+    /// caller should collect with `fetch_initial_cursor`.
+    pub fn hardware_reset(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        info!("Starting WiFi module reset sequence...");
+
+        // Reset the WiFi module (as per es-wifi-driver timing)
+        self.pins.reset.set_low().map_err(|_| WifiError::Pin)?;
+        delay.delay_ms(50);
+        self.pins.reset.set_high().map_err(|_| WifiError::Pin)?;
+        delay.delay_ms(50);
+        info!("WiFi module reset completed");
+
+        // Wake up the module (as per es-wifi-driver timing)
+        self.pins.wakeup.set_high().map_err(|_| WifiError::Pin)?;
+        delay.delay_ms(WAKEUP_LATENCY_MS);
+        info!("WiFi module wake-up signal sent");
+        Ok(())
+    }
+
+    /// Put the module into its low-power standby between bursts of traffic
+    ///
+    /// Enables power save (`ZP=1`) and then drops the wake-up line. The
+    /// module accepts no commands until `exit_low_power` is called.
+    pub fn enter_low_power(&mut self) -> Result<(), WifiError> {
+        info!("Entering WiFi low-power mode...");
+        let _response = self.send_at_command("ZP=1\r")?;
+        self.pins.wakeup.set_low().map_err(|_| WifiError::Pin)?;
+        Ok(())
+    }
+
+    /// Wake the module from `enter_low_power` and disable power save again
+    ///
+    /// Raises the wake-up line and allows `WAKEUP_LATENCY_MS` for the module
+    /// to come out of standby (the same settling time `hardware_reset` gives
+    /// it) before issuing `ZP=0`.
+    pub fn exit_low_power(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        info!("Leaving WiFi low-power mode...");
+        self.pins.wakeup.set_high().map_err(|_| WifiError::Pin)?;
+        self.delay_fed(delay, WAKEUP_LATENCY_MS);
+        let _response = self.send_at_command("ZP=0\r")?;
+        Ok(())
+    }
+
+    /// Restore the module's factory defaults and bring it back up
+    ///
+    /// Issues `Z0`, then runs `hardware_reset` and waits for the fresh
+    /// command prompt. Every setting made through the
+    /// driver is lost, so verbosity is disabled again and open sockets and
+    /// static addressing are forgotten.
+    pub fn factory_reset(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        info!("Restoring WiFi module factory defaults...");
+        let _response = self.send_at_command("Z0\r")?;
+        self.hardware_reset(delay)?;
+
+        let cursor = self.fetch_initial_cursor(delay, INITIAL_CURSOR_TIMEOUT_MS)?;
+        debug!("Cursor after factory reset: '{}'", cursor.as_str());
+
+        self.state = WifiState::Disconnected;
+        self.connect_state = ConnectState::Idle;
+        self.sockets_in_use = [false; MAX_SOCKETS];
+        self.static_ip = None;
+
+        // Disable verbosity as per es-wifi-driver
+        let _response = self.send_at_command("MT=1\r")?;
+
+        info!("WiFi module factory reset completed");
+        Ok(())
+    }
+
+    /// Resynchronise the 16-bit SPI framing without resetting the module
+    ///
+    /// Releases chip select, drains whatever is left of an interrupted
+    /// reply, then sends an empty command line and expects a fresh `>`
+    /// prompt back before checking that `Z5` parses. Module settings and
+    /// the network connection survive, so try this on repeated
+    /// `MalformedResponse` errors before resorting to `hardware_reset`.
+    pub fn recover(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        info!("Recovering WiFi SPI framing...");
+
+        self.deselect_module()?;
+        self.delay_fed(delay, 1);
+
+        let flushed = self.with_module_selected(|module| {
+            let mut flushed = 0;
+            while flushed < RECOVERY_FLUSH_WORDS && module.check_data_ready_pin() {
+                module.feed_watchdog();
+                let mut idle: [u8; 2] = [0x0A, 0x0A];
+                module.transfer_with_retry(&mut idle)?;
+                flushed += 1;
+            }
+            Ok(flushed)
+        })?;
+        debug!("Flushed {} words", flushed);
+
+        // Any reply, even an error, ends in the prompt
+        self.send_command_16bit("\r")?;
+        let cursor = self.fetch_initial_cursor(delay, RECOVERY_TIMEOUT_MS)?;
+        debug!("Cursor after recovery: '{}'", cursor.as_str());
+
+        self.get_mac_address()?;
+        info!("WiFi SPI framing recovered");
+        Ok(())
+    }
+
+    /// Current connection state as tracked by the driver
+    pub fn state(&self) -> WifiState {
+        self.state
+    }
+
+    /// Whether the driver believes it is connected to a network
+    pub fn is_connected(&self) -> bool {
+        self.state == WifiState::Connected
+    }
+
+    /// Read and parse the module identity string (`MR`)
+    ///
+    /// The reply is comma separated, starting with the product ID, firmware
+    /// revision and API revision, e.g. `ISM43362-M3G-L44-SPI,C3.5.2.5.STM,v3.5.2,...`.
+    pub fn get_module_info(&mut self) -> Result<ModuleInfo, WifiError> {
+        let response = self.send_at_command("MR\r")?; // Get module version
+        debug!("Module version info: {}", response.as_str());
+
+        let mut fields = response.split(',').map(str::trim);
+        Ok(ModuleInfo {
+            product_id: parse_field(fields.next())?,
+            firmware_version: parse_field(fields.next())?,
+            api_version: parse_field(fields.next())?,
+        })
+    }
+
+    /// Whether the module is signalling data ready, debounced
+    ///
+    /// The pin is read `data_ready_samples` times; if the reads disagree the
+    /// line is treated as still at its last agreed level.
+    pub fn check_data_ready_pin(&self) -> bool {
+        // According to ISM43362 spec: CMD/DATA READY pin HIGH = data ready
+        // A pin that can't be read is treated as not ready
+        let level = self.pins.data_ready.is_high().unwrap_or(false);
+        for _ in 1..self.data_ready_samples {
+            if self.pins.data_ready.is_high().unwrap_or(false) != level {
+                return self.data_ready_level.get();
+            }
+        }
+        self.data_ready_level.set(level);
+        level
+    }
+
+    /// Wait for the data-ready line to go high, sleeping between interrupts
+    ///
+    /// The core sleeps in `WFI` and is woken by the data-ready EXTI interrupt
+    /// or the 1ms timestamp tick, so this never spins while the module is busy.
+    /// Returns `WifiError::Timeout` if the line is still low after `timeout_ms`.
+    pub fn wait_data_ready(&self, timeout_ms: u32) -> Result<(), WifiError> {
+        let start = now_ms();
+        loop {
+            self.feed_watchdog();
+
+            // The event only wakes us early; the pin level is authoritative
+            clear_data_ready_event();
+            if self.check_data_ready_pin() {
+                return Ok(());
+            }
+
+            if elapsed_ms(start) >= timeout_ms {
+                warn!("Timed out waiting for data ready after {}ms", timeout_ms);
+                return Err(WifiError::Timeout);
+            }
+
+            if !DATA_READY_EVENT.load(Ordering::Acquire) {
+                wfi();
+            }
+        }
+    }
+
+    /// Fetch initial cursor after power-up/reset
+    ///
+    /// Waits up to `timeout_ms` of wall-clock time for the module to present
+    /// its cursor; some module revisions take several seconds after reset.
+    /// Fails with `MalformedResponse` unless the cursor ends in the eS-WiFi
+    /// `>` ready prompt.
+    pub fn fetch_initial_cursor(
+        &mut self,
+        delay: &mut impl DelayMs<u32>,
+        timeout_ms: u32,
+    ) -> Result<String<64>, WifiError> {
+        info!("Fetching initial cursor...");
+
+        let started = now_ms();
+
+        // Wait for CMD/DATA READY pin to go HIGH (data ready)
+        self.wait_data_ready(timeout_ms)?;
+
+        info!("Data ready pin is HIGH, fetching cursor...");
+
+        let mut cursor = String::<64>::new();
+
+        // Clock out 0x0A (Line Feed) until CMD/DATA READY pin goes LOW
+        // Using 8-bit transfers but following 16-bit protocol (send MSB first, then LSB)
+        self.with_module_selected(|module| {
+            delay.delay_ms(1);
+            while module.check_data_ready_pin() {
+                module.feed_watchdog();
+
+                if elapsed_ms(started) >= timeout_ms {
+                    warn!("Cursor not complete after {}ms", timeout_ms);
+                    return Err(WifiError::Timeout);
+                }
+
+                // Send 16-bit word as two 8-bit transfers: MSB first, then LSB
+                let mut msb = [0x0A]; // MSB: Line Feed
+                module.transfer_with_retry(&mut msb)?;
+
+                let mut lsb = [0x00]; // LSB: 0x00
+                module.transfer_with_retry(&mut lsb)?;
+
+                // Store received data from both bytes
+                for &received_byte in &[msb[0], lsb[0]] {
+                    if (32..=126).contains(&received_byte) {
+                        cursor
+                            .push(received_byte as char)
+                            .map_err(|_| WifiError::BufferFull)?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        delay.delay_ms(1);
+
+        info!("Received cursor: '{}'", cursor.as_str());
+        if !cursor.trim_end().ends_with('>') {
+            warn!("Cursor is missing the ready prompt; is the SPI in REQUIRED_SPI_MODE?");
+            return Err(WifiError::MalformedResponse);
+        }
+        Ok(cursor)
+    }
+
+    pub fn test_communication(&mut self) -> Result<(), WifiError> {
+        info!("Testing WiFi module communication...");
+
+        // Check initial data ready pin state
+        let data_ready_initial = self.check_data_ready_pin();
+        info!(
+            "Initial data ready pin state: {}",
+            if data_ready_initial {
+                "READY"
+            } else {
+                "NOT READY"
+            }
+        );
+
+        // Send a simple eS-WiFi command to test communication
+        info!("Sending test eS-WiFi command...");
+
+        // Get MAC address
+        let mac = self.get_mac_address()?;
+        info!(
+            "MAC address: {=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        );
+
+        Ok(())
+    }
+
+    /// Read the module's MAC address (`Z5`)
+    pub fn get_mac_address(&mut self) -> Result<[u8; 6], WifiError> {
+        let response = self.send_at_command("Z5\r")?;
+        parse_mac(response.trim()).ok_or_else(|| {
+            warn!("Malformed MAC address: {}", response.as_str());
+            WifiError::MalformedResponse
+        })
+    }
+
+    /// Exchange `words` with the module, retrying up to `spi_retries` times
+    ///
+    /// The outgoing bytes are restored before each retry, since a transfer
+    /// overwrites them with what was received.
+    fn transfer_with_retry<const N: usize>(
+        &mut self,
+        words: &mut [u8; N],
+    ) -> Result<(), WifiError> {
+        let outgoing = *words;
+        let mut attempt = 0;
+        loop {
+            if self.spi.transfer(words).is_ok() {
+                return Ok(());
+            }
+            if attempt >= self.spi_retries {
+                warn!("SPI transfer failed after {} retries", attempt);
+                return Err(WifiError::SpiTransfer);
+            }
+            attempt += 1;
+            debug!(
+                "SPI transfer failed, retry {}/{}",
+                attempt, self.spi_retries
+            );
+            *words = outgoing;
+        }
+    }
+
+    /// Assert chip select and give the module `CS_HOLD_US` before clocking
+    fn select_module(&mut self) -> Result<(), WifiError> {
+        self.pins.cs.set_low().map_err(|_| WifiError::Pin)?;
+        self.delay.delay_us(CS_HOLD_US);
+        Ok(())
+    }
+
+    /// Release chip select and hold it high for `CS_HOLD_US`
+    fn deselect_module(&mut self) -> Result<(), WifiError> {
+        self.pins.cs.set_high().map_err(|_| WifiError::Pin)?;
+        self.delay.delay_us(CS_HOLD_US);
+        Ok(())
+    }
+
+    /// Whether more of the current response is coming
+    ///
+    /// True while data-ready is high, and also when it rises again within
+    /// `CONTINUATION_WINDOW_US` of dropping, which marks the next chunk of
+    /// a long response rather than its end.
+    fn data_ready_or_continuation(&mut self) -> bool {
+        if self.check_data_ready_pin() {
+            return true;
+        }
+        let mut waited = 0;
+        while waited < CONTINUATION_WINDOW_US {
+            self.delay.delay_us(CONTINUATION_POLL_US);
+            waited += CONTINUATION_POLL_US;
+            if self.check_data_ready_pin() {
+                debug!("Response continues after {}us", waited);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Run `f` with chip select asserted, releasing it whatever the outcome
+    ///
+    /// CS left low after a failed transfer or a timeout would wedge the bus,
+    /// so it is deasserted before any error from `f` is returned. Every
+    /// transaction goes through here rather than through a `Drop` guard,
+    /// because a guard would have to swallow a failed deassert and could not
+    /// wait out `CS_HOLD_US`.
+    fn with_module_selected<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, WifiError>,
+    ) -> Result<T, WifiError> {
+        self.select_module()?;
+        let result = f(self);
+        let deselected = self.deselect_module();
+        let value = result?;
+        deselected?;
+        Ok(value)
+    }
+
+    /// Send command using 16-bit SPI transfers as per ISM43362 spec
+    ///
+    /// The bytes are streamed straight from `command`, so its length is not
+    /// limited by any buffer.
+    fn send_command_16bit(&mut self, command: &str) -> Result<(), WifiError> {
+        // Nothing would be clocked out, so the module would never answer.
+        // A bare "\r" is still sent, as `recover` uses it to get a prompt
+        if command.is_empty() {
+            warn!("Refusing to send an empty command");
+            return Err(WifiError::InvalidParameter);
+        }
+        info!("Sending 16-bit command: {}", redact_command(command));
+
+        // Send command bytes using 16-bit protocol as per es-wifi-driver,
+        // with the es-wifi-driver chip-select timing
+        self.with_module_selected(|module| {
+            for chunk in command.as_bytes().chunks(2) {
+                let mut xfer = pack_word(chunk[0], chunk.get(1).copied());
+                module.transfer_with_retry(&mut xfer)?;
+            }
+            Ok(())
+        })?;
+
+        // Check data ready pin state after sending command
+        debug!(
+            "Data ready pin after command: {}",
+            if self.check_data_ready_pin() {
+                "HIGH"
+            } else {
+                "LOW"
+            }
+        );
+
+        Ok(())
+    }
+
+    /// Send a command header followed by a binary payload in a single
+    /// 16-bit SPI transaction, as required by the socket write command
+    fn send_data_16bit(&mut self, header: &[u8], data: &[u8]) -> Result<(), WifiError> {
+        debug!("Sending {} byte payload", data.len());
+
+        // Same framing as `send_command_16bit`. Words go out in
+        // `SPI_BULK_SIZE` blocks, with the remainder sent a word at a time
+        self.with_module_selected(|module| {
+            let mut bytes = header.iter().chain(data.iter()).copied();
+            let mut block = [0u8; SPI_BULK_SIZE];
+            let mut filled = 0;
+            while let Some(first) = bytes.next() {
+                block[filled..filled + 2].copy_from_slice(&pack_word(first, bytes.next()));
+                filled += 2;
+                if filled == SPI_BULK_SIZE {
+                    module.transfer_with_retry(&mut block)?;
+                    filled = 0;
+                }
+            }
+            for word in block[..filled].chunks_exact(2) {
+                let mut xfer: [u8; 2] = [word[0], word[1]];
+                module.transfer_with_retry(&mut xfer)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Stream a response to `f` as it arrives, using 16-bit SPI transfers
+    ///
+    /// NAK padding bytes are filtered out and the rest are passed to `f` one
+    /// 16-bit word (one or two bytes) at a time, so responses of any length
+    /// can be consumed without buffering them.
+    ///
+    /// Fails with `Timeout` if the whole response has not arrived within
+    /// `timeout_ms`, including a data-ready line that never drops, and with
+    /// `Busy` if the reply opens with `BUSY_NAK_WORDS` words of NAK padding.
+    fn read_response_into(
+        &mut self,
+        timeout_ms: u32,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), WifiError> {
+        let started = now_ms();
+
+        // Wait for data ready signal
+        debug!("Waiting for data ready signal...");
+        self.wait_data_ready(timeout_ms)?;
+
+        info!("Data ready for response, reading...");
+
+        // Clock out 0x0A (Line Feed) until CMD/DATA READY pin goes LOW
+        // Using 16-bit protocol as per es-wifi-driver
+        self.with_module_selected(|module| {
+            // Leading all-NAK words; `None` once real data has arrived
+            let mut leading_naks = Some(0);
+            while module.data_ready_or_continuation() {
+                module.feed_watchdog();
+
+                if elapsed_ms(started) >= timeout_ms {
+                    warn!("Response not complete after {}ms", timeout_ms);
+                    return Err(WifiError::Timeout);
+                }
+
+                let mut xfer: [u8; 2] = [0x0A, 0x0A]; // Send 0x0A in both bytes
+                module.transfer_with_retry(&mut xfer)?;
+
+                // Store received data, checking for NAK (0x15)
+                // Process in reverse order as per es-wifi-driver (16 -> 2*8 bits)
+                match [xfer[1], xfer[0]] {
+                    [NAK, NAK] => {
+                        if let Some(naks) = leading_naks.as_mut() {
+                            *naks += 1;
+                            if *naks >= BUSY_NAK_WORDS {
+                                warn!("Module sent only NAKs, it is busy");
+                                return Err(WifiError::Busy);
+                            }
+                        }
+                        continue;
+                    }
+                    [NAK, byte] | [byte, NAK] => f(&[byte]),
+                    word => f(&word),
+                }
+                leading_naks = None;
+            }
+            Ok(())
+        })
+    }
+
+    /// Read response using 16-bit SPI transfers as per ISM43362 spec
+    ///
+    /// Returns every data line before the final `OK` (see
+    /// `parse_response_body`). Only for commands whose reply is known to be
+    /// text: a reply that is not valid UTF-8 is rejected rather than mangled. Binary payloads such as
+    /// socket data go through `read_data_16bit`.
+    fn read_response_16bit(&mut self) -> Result<String<RX>, WifiError> {
+        self.read_response_within(RESPONSE_TIMEOUT_MS)
+    }
+
+    /// Collect a whole reply, NAK padding removed, without interpreting it
+    fn read_reply_bytes(&mut self, timeout_ms: u32) -> Result<Vec<u8, RX>, WifiError> {
+        // Keep draining on overflow so the module finishes its response
+        let mut raw = Vec::<u8, RX>::new();
+        let mut overflowed = false;
+        self.read_response_into(timeout_ms, |bytes| {
+            if raw.extend_from_slice(bytes).is_err() {
+                overflowed = true;
+            }
+        })?;
+
+        if overflowed {
+            warn!("Response exceeded {} bytes", raw.capacity());
+            return Err(WifiError::BufferFull);
+        }
+        Ok(raw)
+    }
+
+    /// `read_response_16bit` with a caller-chosen timeout
+    fn read_response_within(&mut self, timeout_ms: u32) -> Result<String<RX>, WifiError> {
+        self.read_until_prompt(timeout_ms)?.ok_or_else(|| {
+            warn!("Expected a reply, got only the prompt");
+            WifiError::MalformedResponse
+        })
+    }
+
+    /// Read a reply that may be just the `>` prompt asking for a payload
+    ///
+    /// Returns `None` for a bare prompt, so the caller can go on to send the
+    /// data, and otherwise the data lines of an ordinary `OK`-terminated
+    /// reply, as `read_response_16bit` does.
+    fn read_until_prompt(&mut self, timeout_ms: u32) -> Result<Option<String<RX>>, WifiError> {
+        let raw = self.read_reply_bytes(timeout_ms)?;
+        let response = core::str::from_utf8(&raw).map_err(|_| {
+            // Not dumped, as the reply may be one that carries a secret
+            warn!("Response is not text ({} bytes)", raw.len());
+            WifiError::MalformedResponse
+        })?;
+
+        if is_bare_prompt(response) {
+            debug!("Module is prompting for a payload");
+            return Ok(None);
+        }
+        parse_response_body(response).map(Some)
+    }
+
+    /// Read a response as raw bytes, preserving binary socket data
+    ///
+    /// Unlike `read_response_16bit` no text decoding or line validation is
+    /// done, so every byte value survives; only the NAK padding the module
+    /// adds where a chunk of the reply ends mid-block is dropped (see
+    /// `append_data_block`).
+    fn read_data_16bit<const N: usize>(&mut self) -> Result<Vec<u8, N>, WifiError> {
+        let started = now_ms();
+        debug!("Waiting for data ready signal...");
+        self.wait_data_ready(RESPONSE_TIMEOUT_MS)?;
+
+        let mut data = Vec::<u8, N>::new();
+        let mut overflowed = false;
+        self.with_module_selected(|module| {
+            while module.data_ready_or_continuation() {
+                module.feed_watchdog();
+
+                if elapsed_ms(started) >= RESPONSE_TIMEOUT_MS {
+                    warn!("Response not complete after {}ms", RESPONSE_TIMEOUT_MS);
+                    return Err(WifiError::Timeout);
+                }
+
+                // Clock a whole block at a time; once the module runs out of
+                // data mid-block it pads the rest with NAKs and drops
+                // data-ready, so the line right after the block says whether
+                // the block ends in padding. Keep draining on overflow so the
+                // module finishes its response
+                let mut block = [0x0A; SPI_BULK_SIZE];
+                module.transfer_with_retry(&mut block)?;
+                let chunk_ended = !module.check_data_ready_pin();
+                overflowed |= !append_data_block(&mut data, &block, chunk_ended);
+            }
+            Ok(())
+        })?;
+
+        // Every data reply ends with the `> ` prompt, so trailing NAKs are
+        // always padding, even if data-ready was slow to drop after them
+        while data.last() == Some(&NAK) {
+            data.pop();
+        }
+
+        if overflowed {
+            warn!("Response exceeded {} bytes", N);
+            return Err(WifiError::BufferFull);
+        }
+
+        Ok(data)
+    }
+
+    /// Join `ssid` using the given security mode, blocking until connected
+    ///
+    /// `password` is ignored for `SecurityMode::Open` and must be non-empty
+    /// for every other mode. Shorthand for `connect` with a `ConnectConfig`
+    /// that leaves every other option at its default.
+    pub fn connect_to_network(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        security: SecurityMode,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), WifiError> {
+        let config = ConnectConfig::new(ssid)
+            .password(password)
+            .security(security);
+        self.connect(&config, delay)
+    }
+
+    /// Join the network described by `config`, blocking until connected
+    ///
+    /// This drives `poll_connect` to completion.
+    pub fn connect(
+        &mut self,
+        config: &ConnectConfig,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), WifiError> {
+        self.begin_connect_with(config)?;
+        self.run_connect(delay)
+    }
+
+    /// Rejoin the network from the last `connect_to_network`/`begin_connect`
+    ///
+    /// Retries up to `set_reconnect_attempts` times, doubling the delay
+    /// between attempts from `RECONNECT_INITIAL_BACKOFF_MS` up to
+    /// `RECONNECT_MAX_BACKOFF_MS`. Returns the last error if every attempt fails.
+    pub fn reconnect(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        if self.last_ssid.is_empty() {
+            warn!("No previous network to reconnect to");
+            return Err(WifiError::NotConnected);
+        }
+
+        let mut backoff_ms = RECONNECT_INITIAL_BACKOFF_MS;
+        let mut attempt = 1;
+        loop {
+            info!(
+                "Reconnect attempt {}/{} to {}",
+                attempt,
+                self.reconnect_attempts,
+                self.last_ssid.as_str()
+            );
+            self.connect_state = ConnectState::Disconnect;
+            match self.run_connect(delay) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.reconnect_attempts => {
+                    warn!("Giving up reconnecting after {} attempts", attempt);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Reconnect failed: {}, retrying in {}ms", e, backoff_ms);
+                    self.delay_fed(delay, backoff_ms);
+                    backoff_ms = backoff_ms.saturating_mul(2).min(RECONNECT_MAX_BACKOFF_MS);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Set how many connection attempts `reconnect` makes (minimum 1)
+    pub fn set_reconnect_attempts(&mut self, attempts: u32) {
+        self.reconnect_attempts = attempts.max(1);
+    }
+
+    /// Drive the connect state machine to completion
+    fn run_connect(&mut self, delay: &mut impl DelayMs<u32>) -> Result<(), WifiError> {
+        loop {
+            match self.poll_connect() {
+                Ok(()) => return Ok(()),
+                Err(nb::Error::WouldBlock) => self.delay_fed(delay, CONNECT_POLL_DELAY_MS),
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Start a non-blocking connection to `ssid`
+    ///
+    /// The credentials are copied into the driver; call `poll_connect`
+    /// repeatedly (e.g. from the main loop) to carry out the connection.
+    pub fn begin_connect(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        security: SecurityMode,
+    ) -> Result<(), WifiError> {
+        let config = ConnectConfig::new(ssid)
+            .password(password)
+            .security(security);
+        self.begin_connect_with(&config)
+    }
+
+    /// `begin_connect` for the network described by `config`
+    pub fn begin_connect_with(&mut self, config: &ConnectConfig) -> Result<(), WifiError> {
+        info!("Starting WiFi connection process...");
+
+        if config.security != SecurityMode::Open && config.password.is_empty() {
+            warn!("{} network requires a password", config.security);
+            return Err(WifiError::InvalidParameter);
+        }
+        check_credential_lengths(config.ssid, config.password)?;
+        if config.security.encryption_code().is_none() {
+            warn!("{} networks can't be joined", config.security);
+            return Err(WifiError::Unsupported);
+        }
+        if config.hidden {
+            warn!("The module can't probe for a hidden SSID");
+            return Err(WifiError::Unsupported);
+        }
+
+        self.last_ssid = String::try_from(config.ssid).map_err(|_| WifiError::BufferFull)?;
+        self.last_password =
+            String::try_from(config.password).map_err(|_| WifiError::BufferFull)?;
+        self.last_security = config.security;
+        self.connect_join_retries = config.join_retries;
+        self.connect_timeout_ms = config.timeout_ms;
+        self.connect_poll_interval_ms = config.poll_interval_ms;
+        self.connect_state = ConnectState::Disconnect;
+        Ok(())
+    }
+
+    /// Advance a connection started with `begin_connect` by one step
+    ///
+    /// Each call issues at most one command. Returns `WouldBlock` while the
+    /// connection is in progress and `Ok(())` once an IP address has been
+    /// assigned. On error the state machine returns to `ConnectState::Idle`.
+    pub fn poll_connect(&mut self) -> nb::Result<(), WifiError> {
+        match self.advance_connect() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(nb::Error::WouldBlock),
+            Err(e) => {
+                self.connect_state = ConnectState::Idle;
+                Err(nb::Error::Other(e))
+            }
+        }
+    }
+
+    /// Current step of the non-blocking connect state machine
+    pub fn connect_state(&self) -> ConnectState {
+        self.connect_state
+    }
+
+    /// Run one step of the connect state machine, returning true once connected
+    fn advance_connect(&mut self) -> Result<bool, WifiError> {
+        let mut cmd: String<80> = String::new();
+        let next = match self.connect_state {
+            ConnectState::Idle => return Err(WifiError::NotConnected),
+            ConnectState::Disconnect => {
+                // Disconnect from any existing network using eS-WiFi command
+                info!("Disconnecting from any existing network...");
+                match self.send_at_command("CD\r") {
+                    // The module rejects CD when there is nothing to leave
+                    Ok(_) | Err(WifiError::CommandFailed(_) | WifiError::NotConnected) => {}
+                    Err(e) => return Err(e),
+                }
+                self.state = WifiState::Disconnected;
+                ConnectState::SetSsid
+            }
+            ConnectState::SetSsid => {
+                // Set SSID using eS-WiFi command
+                info!("Setting SSID: {}", self.last_ssid.as_str());
+                write!(cmd, "C1={}\r", self.last_ssid).map_err(|_| WifiError::BufferFull)?;
+                let _response = self.send_at_command(cmd.as_str())?;
+                if self.last_security == SecurityMode::Open {
+                    ConnectState::SetEncryption
+                } else {
+                    ConnectState::SetPassword
+                }
+            }
+            ConnectState::SetPassword => {
+                // Set password using eS-WiFi command
+                info!("Setting password...");
+                write!(cmd, "C2={}\r", self.last_password).map_err(|_| WifiError::BufferFull)?;
+                let _response = self.send_at_command(cmd.as_str())?;
+                ConnectState::SetEncryption
+            }
+            ConnectState::SetEncryption => {
+                // Set encryption type (C3=) as per es-wifi-driver
+                info!("Setting encryption type...");
+                let code = self
+                    .last_security
+                    .encryption_code()
+                    .ok_or(WifiError::Unsupported)?;
+                write!(cmd, "C3={}\r", code).map_err(|_| WifiError::BufferFull)?;
+                let _response = self.send_at_command(cmd.as_str())?;
+                ConnectState::SetJoinRetries
+            }
+            ConnectState::SetJoinRetries => {
+                info!("Setting join retries to {}...", self.connect_join_retries);
+                NetworkSetting::JoinRetries.write_command(&mut cmd, self.connect_join_retries)?;
+                let _response = self.send_at_command(cmd.as_str())?;
+                ConnectState::Join
+            }
+            ConnectState::Join => {
+                // Connect to WiFi network using eS-WiFi command
+                info!("Connecting to WiFi network: {}", self.last_ssid.as_str());
+                let _response = self.send_at_command("C0\r")?; // Connect command
+                info!("Waiting for WiFi connection...");
+                let now = now_ms();
+                ConnectState::WaitForIp {
+                    started_ms: now,
+                    last_poll_ms: now,
+                }
+            }
+            ConnectState::WaitForIp {
+                started_ms,
+                last_poll_ms,
+            } => {
+                if elapsed_ms(started_ms) >= self.connect_timeout_ms {
+                    warn!(
+                        "WiFi connection timeout after {}ms",
+                        self.connect_timeout_ms
+                    );
+                    return Err(WifiError::Timeout);
+                }
+                if elapsed_ms(last_poll_ms) < self.connect_poll_interval_ms {
+                    return Ok(false);
+                }
+
+                if let Some(ip) = self.check_connection_status()? {
+                    info!("WiFi connection successful! IP address: {}", ip);
+                    self.state = WifiState::Connected;
+                    self.connect_state = ConnectState::Idle;
+                    info!("WiFi connection process completed");
+                    return Ok(true);
+                }
+                ConnectState::WaitForIp {
+                    started_ms,
+                    last_poll_ms: now_ms(),
+                }
+            }
+        };
+
+        self.connect_state = next;
+        Ok(false)
+    }
+
+    /// Query the connection status (`C?`) once
+    ///
+    /// Returns the assigned address when connected, `None` while still
+    /// associating, and an error if the module reports the join failed.
+    fn check_connection_status(&mut self) -> Result<Option<WifiIpv4>, WifiError> {
+        match self.send_at_command("C?\r") {
+            Ok(response) => match parse_connection_status(response.as_str(), &self.last_password) {
+                // Associated means the status flag is set and a real address
+                // was assigned; with a static configuration it must be ours
+                Some(info)
+                    if info.connected
+                        && !info.ip_config.ip.is_unspecified()
+                        && self
+                            .static_ip
+                            .is_none_or(|configured| configured == info.ip_config.ip) =>
+                {
+                    Ok(Some(info.ip_config.ip))
+                }
+                Some(info) => {
+                    debug!(
+                        "Connection status: connected={} IP {}",
+                        info.connected, info.ip_config.ip
+                    );
+                    Ok(None)
+                }
+                // The reply includes the password, so it is neither logged
+                // nor carried in the error
+                None if response.contains("Failed") => {
+                    warn!("WiFi connection failed");
+                    Err(WifiError::command_failed("Connection failed"))
+                }
+                None => {
+                    debug!(
+                        "Unrecognised connection status: {}",
+                        redact_reply("C?", response.as_str())
+                    );
+                    Ok(None)
+                }
+            },
+            // A missed poll is retried until the connect timeout runs out
+            Err(e) => {
+                debug!("Failed to check connection status: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the address, netmask, gateway and DNS servers in use from `C?`
+    ///
+    /// Fails with `NotConnected` until the module is associated and has an
+    /// address.
+    pub fn get_ip_config(&mut self) -> Result<IpConfig, WifiError> {
+        let info = self.connection_status()?;
+        if !info.connected || info.ip_config.ip.is_unspecified() {
+            return Err(WifiError::NotConnected);
+        }
+        Ok(info.ip_config)
+    }
+
+    /// Read the SSID of the network the module is associated with from `C?`
+    ///
+    /// Also reports a network the module rejoined on its own after a reset.
+    /// Fails with `NotConnected` while the module is not associated.
+    pub fn get_connected_ssid(&mut self) -> Result<String<SSID_MAX_LEN>, WifiError> {
+        let info = self.connection_status()?;
+        if !info.connected {
+            return Err(WifiError::NotConnected);
+        }
+        Ok(info.ssid)
+    }
+
+    /// Query `C?` and bring `state()` in line with the module's link status
+    ///
+    /// Returns whether the module is associated and has an address. A link
+    /// that has dropped, e.g. because the access point went away, moves the
+    /// state to `Disconnected` so the caller knows to `reconnect`. In
+    /// access-point mode nothing is queried and the link counts as up.
+    pub fn check_link(&mut self) -> Result<bool, WifiError> {
+        if self.state == WifiState::AccessPoint {
+            return Ok(true);
+        }
+
+        let info = self.connection_status()?;
+        let connected = info.connected && !info.ip_config.ip.is_unspecified();
+        if connected != self.is_connected() {
+            info!("WiFi link is {}", if connected { "up" } else { "down" });
+        }
+        self.state = if connected {
+            WifiState::Connected
+        } else {
+            WifiState::Disconnected
+        };
+        Ok(connected)
+    }
+
+    /// Read the network settings stored on the module from `C?`
+    ///
+    /// Unlike `get_connected_ssid` this works before the module has joined,
+    /// so after a reset it shows whether it would rejoin on its own. The
+    /// stored password is deliberately not returned.
+    pub fn get_stored_network(&mut self) -> Result<StoredNetwork, WifiError> {
+        let info = self.connection_status()?;
+        Ok(StoredNetwork {
+            ssid: info.ssid,
+            security: info.security,
+            auto_connect: info.auto_connect,
+        })
+    }
+
+    /// Query and parse the `C?` connection status
+    fn connection_status(&mut self) -> Result<ConnectionInfo, WifiError> {
+        let response = self.send_at_command("C?\r")?;
+        parse_connection_status(response.as_str(), &self.last_password).ok_or_else(|| {
+            // The reply includes the password, so leave it out of the log
+            warn!("Unrecognised connection status ({} bytes)", response.len());
+            WifiError::MalformedResponse
+        })
+    }
+
+    /// Use a static address instead of DHCP for the next connection
+    ///
+    /// Disables DHCP (`C4=0`) and sets the address (`C6=`), netmask (`C7=`),
+    /// gateway (`C8=`) and primary DNS server (`C9=`). Call this before
+    /// `connect_to_network`, as the module applies it when `C0` is issued.
+    /// `set_dhcp(true)` switches back to DHCP.
+    pub fn set_static_ip(
+        &mut self,
+        ip: WifiIpv4,
+        netmask: WifiIpv4,
+        gateway: WifiIpv4,
+        dns: WifiIpv4,
+    ) -> Result<(), WifiError> {
+        info!(
+            "Configuring static IP {} netmask {} gateway {} DNS {}",
+            ip, netmask, gateway, dns
+        );
+
+        let mut cmd: String<32> = String::new();
+        NetworkSetting::Dhcp.write_command(&mut cmd, 0)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        for (setting, address) in [
+            (NetworkSetting::IpAddress, ip),
+            (NetworkSetting::Netmask, netmask),
+            (NetworkSetting::Gateway, gateway),
+            (NetworkSetting::PrimaryDns, dns),
+        ] {
+            cmd.clear();
+            setting.write_command(&mut cmd, address)?;
+            let _response = self.send_at_command(cmd.as_str())?;
+        }
+
+        self.static_ip = Some(ip);
+        Ok(())
+    }
+
+    /// Turn DHCP for the next connection on (`C4=1`) or off (`C4=0`)
+    ///
+    /// Enabling it undoes `set_static_ip`. Like that, call it before
+    /// `connect_to_network`, as the module applies it when `C0` is issued.
+    pub fn set_dhcp(&mut self, enabled: bool) -> Result<(), WifiError> {
+        info!("Setting DHCP {}", if enabled { "on" } else { "off" });
+
+        let mut cmd: String<8> = String::new();
+        NetworkSetting::Dhcp.write_command(&mut cmd, u8::from(enabled))?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        if enabled {
+            self.static_ip = None;
+        }
+        Ok(())
+    }
+
+    /// Set the DNS servers `resolve_hostname` queries (`C9=`, then `CA=`)
+    ///
+    /// The primary server uses the same command as the DNS argument of
+    /// `set_static_ip`, so call this after that to override it, and before
+    /// `connect_to_network`, as the module applies both when `C0` is issued.
+    /// Without a `secondary` the module's current secondary server is kept.
+    pub fn set_dns_servers(
+        &mut self,
+        primary: WifiIpv4,
+        secondary: Option<WifiIpv4>,
+    ) -> Result<(), WifiError> {
+        info!("Setting DNS servers {} and {}", primary, secondary);
+
+        let mut cmd: String<32> = String::new();
+        NetworkSetting::PrimaryDns.write_command(&mut cmd, primary)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        if let Some(secondary) = secondary {
+            cmd.clear();
+            NetworkSetting::SecondaryDns.write_command(&mut cmd, secondary)?;
+            let _response = self.send_at_command(cmd.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Name the module announces when it requests a DHCP lease (`ZN=`)
+    ///
+    /// Call this before `connect_to_network`, as the name is sent with the
+    /// DHCP request made after `C0`. The name must be 1 to `HOSTNAME_MAX_LEN`
+    /// letters, digits and hyphens, and may not start or end with a hyphen.
+    pub fn set_hostname(&mut self, name: &str) -> Result<(), WifiError> {
+        info!("Setting hostname: {}", name);
+
+        let valid_chars = name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if name.is_empty()
+            || name.len() > HOSTNAME_MAX_LEN
+            || !valid_chars
+            || name.starts_with('-')
+            || name.ends_with('-')
+        {
+            warn!("Invalid hostname: {}", name);
+            return Err(WifiError::InvalidParameter);
+        }
+
+        let mut cmd: String<{ HOSTNAME_MAX_LEN + 8 }> = String::new();
+        write!(cmd, "ZN={}\r", name).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+        Ok(())
+    }
+
+    /// Set the regulatory domain (`CN=`) from a two-letter country code
+    ///
+    /// The domain decides which channels are scanned and used, so call this
+    /// before `scan_networks`, `connect` or `start_access_point`; channels
+    /// 12 and 13 are only found where the domain allows them. `code` must be
+    /// two ASCII uppercase letters, e.g. `b"DE"`.
+    pub fn set_country_code(&mut self, code: &[u8; 2]) -> Result<(), WifiError> {
+        if !code.iter().all(u8::is_ascii_uppercase) {
+            warn!("Country code must be two uppercase letters");
+            return Err(WifiError::InvalidParameter);
+        }
+        let [first, second] = code.map(char::from);
+        info!("Setting country code {}{}", first, second);
+
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "CN={}{}\r", first, second).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+        Ok(())
+    }
+
+    /// Set the transmit power index (`CP=`), 0 (lowest) to `TX_POWER_MAX`
+    ///
+    /// Lower levels cut the current drawn while transmitting at the cost of
+    /// range. The level applies from the next connection.
+    pub fn set_tx_power(&mut self, level: u8) -> Result<(), WifiError> {
+        if level > TX_POWER_MAX {
+            warn!("Power level {} is over the maximum {}", level, TX_POWER_MAX);
+            return Err(WifiError::InvalidParameter);
+        }
+        info!("Setting transmit power level {}", level);
+
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "CP={}\r", level).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+        Ok(())
+    }
+
+    /// Read the transmit power index (`CP` without a value)
+    ///
+    /// Older firmware revisions reply with `CommandFailed` or
+    /// `InvalidParameter` as they cannot report the level.
+    pub fn get_tx_power(&mut self) -> Result<u8, WifiError> {
+        let response = self.send_at_command("CP\r")?;
+        response.trim().parse().map_err(|_| {
+            warn!("Malformed transmit power: {}", response.as_str());
+            WifiError::MalformedResponse
+        })
+    }
+
+    /// Restrict the radio to `band`
+    ///
+    /// The ISM43362 has a 2.4GHz-only radio and no band command, so
+    /// `Band::TwoPointFour` is always in effect and `Band::Five` fails with
+    /// `Unsupported`. Nothing is sent to the module either way; callers
+    /// can use the result to probe for 5GHz support.
+    pub fn set_band(&mut self, band: Band) -> Result<(), WifiError> {
+        match band {
+            Band::TwoPointFour => Ok(()),
+            Band::Five => {
+                warn!("This module has no 5GHz radio");
+                Err(WifiError::Unsupported)
+            }
+        }
+    }
+
+    /// Disconnect from the current network (`CD`)
+    pub fn disconnect(&mut self) -> Result<(), WifiError> {
+        info!("Disconnecting from WiFi network...");
+
+        let _response = self.send_at_command("CD\r")?;
+
+        self.state = WifiState::Disconnected;
+        info!("WiFi disconnected");
+        Ok(())
+    }
+
+    /// Host a network named `ssid` on `channel` (1-13)
+    ///
+    /// Sets the SSID (`AS=`), security mode (`A1=`), key (`A2=`) and channel
+    /// (`AC=`), then activates the access point with `AD`. Clients can then
+    /// join the module, for example to reach a provisioning page.
+    pub fn start_access_point(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        channel: u8,
+        security: SecurityMode,
+    ) -> Result<(), WifiError> {
+        info!("Starting access point {} on channel {}", ssid, channel);
+
+        if !(1..=13).contains(&channel) {
+            warn!("Channel {} is outside 1-13", channel);
+            return Err(WifiError::InvalidParameter);
+        }
+        if security != SecurityMode::Open && password.is_empty() {
+            warn!("{} access point requires a password", security);
+            return Err(WifiError::InvalidParameter);
+        }
+        check_credential_lengths(ssid, password)?;
+        let encryption = security.encryption_code().ok_or_else(|| {
+            warn!("{} access points can't be hosted", security);
+            WifiError::Unsupported
+        })?;
+
+        let mut cmd: String<80> = String::new();
+        write!(cmd, "AS=0,{}\r", ssid).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        cmd.clear();
+        write!(cmd, "A1={}\r", encryption).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        if security != SecurityMode::Open {
+            cmd.clear();
+            write!(cmd, "A2={}\r", password).map_err(|_| WifiError::BufferFull)?;
+            let _response = self.send_at_command(cmd.as_str())?;
+        }
+
+        cmd.clear();
+        write!(cmd, "AC={}\r", channel).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        let _response = self.send_at_command("AD\r")?;
+
+        self.state = WifiState::AccessPoint;
+        info!("Access point started");
+        Ok(())
+    }
+
+    /// Shut down the access point started with `start_access_point`
+    pub fn stop_access_point(&mut self) -> Result<(), WifiError> {
+        info!("Stopping access point...");
+
+        let _response = self.send_at_command("AE\r")?;
+
+        self.state = WifiState::Disconnected;
+        info!("Access point stopped");
+        Ok(())
+    }
+
+    /// Read the RSSI of the current connection in dBm (`CR`)
+    ///
+    /// Returns `WifiError::NotConnected` when the module is not associated,
+    /// which it signals by rejecting the command or reporting a non-negative value.
+    pub fn signal_strength(&mut self) -> Result<i8, WifiError> {
+        let response = match self.send_at_command("CR\r") {
+            Ok(response) => response,
+            Err(WifiError::CommandFailed(reply)) => {
+                debug!("Signal strength unavailable: {}", reply);
+                return Err(WifiError::NotConnected);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let rssi: i8 = response.trim().parse().map_err(|_| {
+            warn!("Malformed RSSI response: {}", response.as_str());
+            WifiError::MalformedResponse
+        })?;
+        if rssi >= 0 {
+            return Err(WifiError::NotConnected);
+        }
+
+        debug!("Signal strength: {} dBm", rssi);
+        Ok(rssi)
+    }
+
+    /// Scan for visible access points
+    ///
+    /// Returns an empty list when no networks are found. If more than
+    /// `MAX_SCAN_RESULTS` are visible the extra entries are dropped.
+    pub fn scan_networks(&mut self) -> Result<Vec<ApInfo, MAX_SCAN_RESULTS>, WifiError> {
+        self.scan_networks_up_to()
+    }
+
+    /// Scan for visible access points, handing each to `on_ap` as it arrives
+    ///
+    /// Only one scan line is buffered at a time, so any number of results
+    /// can be walked in a few hundred bytes of RAM. Returning
+    /// `ControlFlow::Break` from `on_ap` skips the remaining results. The
+    /// rest of the reply is still drained, so the module is left ready for
+    /// the next command.
+    pub fn scan_networks_iter(
+        &mut self,
+        mut on_ap: impl FnMut(ApInfo) -> ControlFlow<()>,
+    ) -> Result<(), WifiError> {
+        info!("Scanning for networks...");
+        self.send_command_16bit("F0\r")?;
+
+        let mut line: Vec<u8, SCAN_LINE_SIZE> = Vec::new();
+        let mut truncated = false;
+        let mut stopped = false;
+        let mut found: usize = 0;
+        // Last line that isn't a result, which ends up being the reply code;
+        // only kept for the error, so it may be truncated whatever `RX` is
+        let mut reply: String<32> = String::new();
+
+        self.read_response_into(RESPONSE_TIMEOUT_MS, |bytes| {
+            for &byte in bytes {
+                if byte != b'\n' {
+                    truncated |= line.push(byte).is_err();
+                    continue;
+                }
+                match core::str::from_utf8(&line).map(str::trim) {
+                    Ok("" | ">") => {}
+                    Ok(text) if text.starts_with('#') => {
+                        if truncated {
+                            warn!("Scan line longer than {} bytes", SCAN_LINE_SIZE);
+                        } else if !stopped {
+                            match parse_scan_line(text) {
+                                Some(ap) => {
+                                    found += 1;
+                                    stopped = on_ap(ap).is_break();
+                                }
+                                None => warn!("Could not parse scan line: {}", text),
+                            }
+                        }
+                    }
+                    Ok(text) => {
+                        reply.clear();
+                        for c in text.chars() {
+                            if reply.push(c).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(_) => warn!("Skipping non-UTF-8 response line"),
+                }
+                line.clear();
+                truncated = false;
+            }
+        })?;
+
+        match reply.as_str() {
+            "OK" => {
+                info!("Found {} networks", found);
+                Ok(())
+            }
+            "" => Err(WifiError::MalformedResponse),
+            reply => Err(failed_reply(reply, None)),
+        }
+    }
+
+    /// Scan for up to `N` access points, in the order the module lists them
+    ///
+    /// `scan_networks` with a caller-chosen capacity. Built on
+    /// `scan_networks_iter`, so only the results are stored, never the raw
+    /// reply. Results past the first `N` are dropped.
+    pub fn scan_networks_up_to<const N: usize>(&mut self) -> Result<Vec<ApInfo, N>, WifiError> {
+        let mut networks = Vec::new();
+        self.scan_networks_iter(|ap| match networks.push(ap) {
+            Ok(()) if networks.is_full() => ControlFlow::Break(()),
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        })?;
+        Ok(networks)
+    }
+
+    /// `scan_networks`, strongest signal first
+    pub fn scan_networks_by_rssi(&mut self) -> Result<Vec<ApInfo, MAX_SCAN_RESULTS>, WifiError> {
+        let mut networks = self.scan_networks()?;
+        networks.sort_unstable_by_key(|ap| core::cmp::Reverse(ap.rssi));
+        Ok(networks)
+    }
+
+    /// Strongest visible access point whose SSID starts with `prefix`
+    ///
+    /// Picks between access points sharing a name, or between several
+    /// known networks with a common prefix. `None` if no SSID matches.
+    pub fn best_network_matching(&mut self, prefix: &str) -> Result<Option<ApInfo>, WifiError> {
+        let best = self
+            .scan_networks()?
+            .into_iter()
+            .filter(|ap| ap.ssid.starts_with(prefix))
+            .max_by_key(|ap| ap.rssi);
+        if let Some(ap) = &best {
+            info!(
+                "Best match for '{}': {} at {} dBm",
+                prefix, ap.ssid, ap.rssi
+            );
+        }
+        Ok(best)
+    }
+
+    /// Scan, then join the strongest visible network from `creds`
+    ///
+    /// Each entry is `(ssid, password, security)`. Networks are tried from
+    /// the strongest signal down until one connects; the error from the last
+    /// attempt is returned if none does, or `NotConnected` if no known
+    /// network is in range.
+    pub fn join_known(
+        &mut self,
+        creds: &[(&str, &str, SecurityMode)],
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), WifiError> {
+        let networks = self.scan_networks_by_rssi()?;
+        let mut last_error = None;
+
+        for (i, ap) in networks.iter().enumerate() {
+            // The module joins by SSID, so only the strongest AP of each counts
+            if networks[..i].iter().any(|seen| seen.ssid == ap.ssid) {
+                continue;
+            }
+            let Some(&(ssid, password, security)) =
+                creds.iter().find(|(ssid, _, _)| *ssid == ap.ssid.as_str())
+            else {
+                continue;
+            };
+
+            info!("Trying known network {} at {} dBm", ssid, ap.rssi);
+            match self.connect_to_network(ssid, password, security, delay) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Could not join {}: {}", ssid, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            None => {
+                warn!("No known network in range");
+                Err(WifiError::NotConnected)
+            }
+        }
+    }
+
+    /// Ping `target` `count` times (`T1=`, `T2=`, `T0`)
+    ///
+    /// Lost packets are reported in the returned statistics rather than as
+    /// an error; only a failure to run the ping is an error.
+    pub fn ping(&mut self, target: WifiIpv4, count: u8) -> Result<PingStats, WifiError> {
+        info!("Pinging {} ({} packets)...", target, count);
+
+        let mut cmd: String<32> = String::new();
+        write!(cmd, "T1={}\r", target).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        cmd.clear();
+        write!(cmd, "T2={}\r", count).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        // The module prints one line per echo: a round-trip time in ms or a
+        // failure message such as "Timeout"
+        self.send_command_16bit("T0\r")?;
+        let raw: Vec<u8, PING_BUFFER_SIZE> = self.read_data_16bit()?;
+
+        let mut stats = PingStats {
+            sent: count,
+            received: 0,
+            min_ms: u32::MAX,
+            avg_ms: 0,
+            max_ms: 0,
+        };
+        let mut total_ms: u32 = 0;
+        for_each_data_line(&raw, |line| match line.parse::<u32>() {
+            Ok(rtt) => {
+                stats.received = stats.received.saturating_add(1);
+                stats.min_ms = stats.min_ms.min(rtt);
+                stats.max_ms = stats.max_ms.max(rtt);
+                total_ms = total_ms.saturating_add(rtt);
+            }
+            Err(_) => debug!("Ping reply: {}", line),
+        })?;
+
+        if stats.received == 0 {
+            stats.min_ms = 0;
+        } else {
+            stats.avg_ms = total_ms / u32::from(stats.received);
+        }
+
+        info!(
+            "Ping {}: {}/{} received, min/avg/max {}/{}/{} ms",
+            target, stats.received, stats.sent, stats.min_ms, stats.avg_ms, stats.max_ms
+        );
+        Ok(stats)
+    }
+
+    /// Check that the network is reachable beyond the access point
+    ///
+    /// Pings the gateway from `get_ip_config` `VERIFY_PING_COUNT` times.
+    /// Being associated with an address only proves the WiFi link; this
+    /// fails with `CommandFailed` when no echo comes back, telling an
+    /// association without working IP routing apart from a usable
+    /// connection.
+    pub fn verify_connectivity(&mut self) -> Result<PingStats, WifiError> {
+        let gateway = self.get_ip_config()?.gateway;
+        let stats = self.ping(gateway, VERIFY_PING_COUNT)?;
+        if stats.received == 0 {
+            warn!("Gateway {} is unreachable", gateway);
+            return Err(WifiError::command_failed("Gateway unreachable"));
+        }
+        Ok(stats)
+    }
+
+    /// Resolve `host` to an IPv4 address using the module's DNS client (`D0`)
+    pub fn resolve_hostname(&mut self, host: &str) -> Result<WifiIpv4, WifiError> {
+        info!("Resolving hostname: {}", host);
+
+        let mut cmd: String<128> = String::new();
+        write!(cmd, "D0={}\r", host).map_err(|_| WifiError::BufferFull)?;
+
+        let response = self.send_at_command(cmd.as_str())?;
+
+        let ip = parse_ipv4(response.trim()).ok_or_else(|| {
+            warn!("Malformed DNS response: {}", response.as_str());
+            WifiError::MalformedResponse
+        })?;
+        info!("{} resolved to {}", host, ip);
+        Ok(ip)
+    }
+
+    /// Query `ntp_server` over UDP and anchor the timebase to its answer
+    ///
+    /// Returns the current Unix time in seconds and stores it with
+    /// `time::set_unix_time`, so log timestamps report wall-clock time.
+    pub fn sync_time(&mut self, ntp_server: &str) -> Result<u32, WifiError> {
+        info!("Syncing time with {}...", ntp_server);
+        let server = self.resolve_hostname(ntp_server)?;
+
+        let socket = self.udp_open(NTP_LOCAL_PORT, server, NTP_PORT)?;
+        let result = self.query_ntp(&socket);
+        let closed = self.close_socket(socket);
+        let unix_secs = result?;
+        closed?;
+
+        set_unix_time(unix_secs);
+        info!("Unix time is {}", unix_secs);
+        Ok(unix_secs)
+    }
+
+    /// Send an SNTP client request on `socket` and wait for the reply
+    fn query_ntp(&mut self, socket: &Socket) -> Result<u32, WifiError> {
+        let mut packet = [0u8; NTP_PACKET_SIZE];
+        packet[0] = 0x1B; // LI = 0, version 3, mode 3 (client)
+        self.udp_send(socket, &packet)?;
+
+        let started = now_ms();
+        loop {
+            let (received, _) = self.udp_recv(socket, &mut packet)?;
+            if received == NTP_PACKET_SIZE {
+                return parse_ntp_reply(&packet);
+            }
+            if elapsed_ms(started) > NTP_TIMEOUT_MS {
+                warn!("No NTP reply within {} ms", NTP_TIMEOUT_MS);
+                return Err(WifiError::Timeout);
+            }
+        }
+    }
+
+    /// Fetch `path` from `host:port` with a plain HTTP/1.1 `GET`
+    ///
+    /// Resolves `host`, sends the request with `Host:` and
+    /// `Connection: close`, and reads until the server closes the connection
+    /// or `response` is full. The headers are dropped and the body moved to
+    /// the start of `response`; the returned length is the body's. A body
+    /// that does not fit is truncated.
+    pub fn http_get(
+        &mut self,
+        host: &str,
+        path: &str,
+        port: u16,
+        response: &mut [u8],
+    ) -> Result<usize, WifiError> {
+        info!("HTTP GET http://{}:{}{}", host, port, path);
+        let server = self.resolve_hostname(host)?;
+
+        let mut request: String<HTTP_REQUEST_SIZE> = String::new();
+        write!(
+            request,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+        .map_err(|_| WifiError::BufferFull)?;
+
+        let socket = self.open_socket(Protocol::Tcp, server, port)?;
+        let result = self.http_exchange(&socket, &[request.as_bytes()], response);
+        let closed = self.close_socket(socket);
+        let received = result?;
+        closed?;
+        if received == response.len() {
+            warn!("HTTP response truncated to {} bytes", received);
+        }
+
+        let body_start = find_header_end(&response[..received]).ok_or_else(|| {
+            warn!("HTTP response has no end of headers");
+            WifiError::MalformedResponse
+        })?;
+        response.copy_within(body_start..received, 0);
+        let body_len = received - body_start;
+        info!("HTTP body is {} bytes", body_len);
+        Ok(body_len)
+    }
+
+    /// `POST` `body` as JSON to `endpoint` and return the HTTP status code
+    ///
+    /// Any status the server answers with is returned, so the caller decides
+    /// whether e.g. a 4xx counts as failure. Only the start of the reply is
+    /// read; the rest is dropped with the connection.
+    pub fn post_telemetry(
+        &mut self,
+        endpoint: &HttpEndpoint,
+        body: &[u8],
+    ) -> Result<u16, WifiError> {
+        info!(
+            "HTTP POST {} bytes to http://{}:{}{}",
+            body.len(),
+            endpoint.host,
+            endpoint.port,
+            endpoint.path
+        );
+        let server = self.resolve_hostname(endpoint.host)?;
+
+        let mut header: String<HTTP_REQUEST_SIZE> = String::new();
+        write!(
+            header,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            endpoint.path,
+            endpoint.host,
+            body.len()
+        )
+        .map_err(|_| WifiError::BufferFull)?;
+
+        let mut response = [0u8; HTTP_STATUS_BUFFER_SIZE];
+        let socket = self.open_socket(Protocol::Tcp, server, endpoint.port)?;
+        let result = self.http_exchange(&socket, &[header.as_bytes(), body], &mut response);
+        let closed = self.close_socket(socket);
+        let received = result?;
+        closed?;
+
+        let status = parse_http_status(&response[..received]).ok_or_else(|| {
+            warn!("HTTP response has no status line");
+            WifiError::MalformedResponse
+        })?;
+        info!("HTTP status {}", status);
+        Ok(status)
+    }
+
+    /// Write `request`, part by part, to `socket` and read the raw reply
+    /// into `response`
+    ///
+    /// Reading stops when the server closes the connection or `response`
+    /// is full. A reply still open after `HTTP_TIMEOUT_MS` is a `Timeout`
+    /// rather than a partial body.
+    fn http_exchange(
+        &mut self,
+        socket: &Socket,
+        request: &[&[u8]],
+        response: &mut [u8],
+    ) -> Result<usize, WifiError> {
+        for part in request {
+            let mut sent = 0;
+            while sent < part.len() {
+                match self.tcp_send(socket, &part[sent..])? {
+                    0 => return Err(WifiError::command_failed("Send stalled")),
+                    written => sent += written,
+                }
+            }
+        }
+
+        // An empty read only means nothing has arrived yet; the reply ends
+        // when the module reports the socket closed
+        let started = now_ms();
+        let mut received = 0;
+        while received < response.len() {
+            self.feed_watchdog();
+            self.select_socket(socket)?;
+            match self.socket_read(&mut response[received..])? {
+                Some(read) => received += read,
+                None => return Ok(received),
+            }
+            if elapsed_ms(started) > HTTP_TIMEOUT_MS {
+                warn!("HTTP response not complete within {} ms", HTTP_TIMEOUT_MS);
+                return Err(WifiError::Timeout);
+            }
+        }
+        Ok(received)
+    }
+
+    /// Open a client socket to `remote_ip:remote_port` on a free slot
+    ///
+    /// Selects the slot (`P0=`), transport protocol (`P1=`), remote address
+    /// (`P3=`) and port (`P4=`), then starts the client with `P6=1`.
+    pub fn open_socket(
+        &mut self,
+        protocol: Protocol,
+        remote_ip: WifiIpv4,
+        remote_port: u16,
+    ) -> Result<Socket, WifiError> {
+        let socket = self.allocate_socket()?;
+        info!(
+            "Opening {} socket {} to {}:{}",
+            protocol,
+            socket.index(),
+            remote_ip,
+            remote_port
+        );
+        if let Err(e) = self.configure_socket(&socket, protocol, None, remote_ip, remote_port) {
+            self.release_socket(socket);
+            return Err(e);
+        }
+        info!("Socket opened");
+        Ok(socket)
+    }
+
+    /// Resolve `host` and open a TCP client socket to it on `port`
+    ///
+    /// `open_socket` with the address looked up by `resolve_hostname`.
+    pub fn tcp_connect(&mut self, host: &str, port: u16) -> Result<Socket, WifiError> {
+        let remote_ip = self.resolve_hostname(host)?;
+        self.open_socket(Protocol::Tcp, remote_ip, port)
+    }
+
+    /// Open a UDP socket bound to `local_port` that sends to `remote_ip:remote_port`
+    ///
+    /// UDP has no connection handshake; `P6=1` only creates the socket with
+    /// the configured parameters, so this returns as soon as they are set.
+    pub fn udp_open(
+        &mut self,
+        local_port: u16,
+        remote_ip: WifiIpv4,
+        remote_port: u16,
+    ) -> Result<Socket, WifiError> {
+        let socket = self.allocate_socket()?;
+        info!(
+            "Opening UDP socket {} on port {} to {}:{}",
+            socket.index(),
+            local_port,
+            remote_ip,
+            remote_port
+        );
+        if let Err(e) = self.configure_socket(
+            &socket,
+            Protocol::Udp,
+            Some(local_port),
+            remote_ip,
+            remote_port,
+        ) {
+            self.release_socket(socket);
+            return Err(e);
+        }
+        info!("UDP socket opened");
+        Ok(socket)
+    }
+
+    /// Resolve `host` and open a UDP socket bound to `local_port` sending to it
+    ///
+    /// `udp_open` with the address looked up by `resolve_hostname`.
+    pub fn udp_start(
+        &mut self,
+        local_port: u16,
+        host: &str,
+        remote_port: u16,
+    ) -> Result<Socket, WifiError> {
+        let remote_ip = self.resolve_hostname(host)?;
+        self.udp_open(local_port, remote_ip, remote_port)
+    }
+
+    /// Open a TLS socket to `remote_ip:remote_port` on a free slot
+    ///
+    /// With `ca_cert` (PEM) the certificate is loaded with `PG=` and the
+    /// server is verified against it; without one the server is not
+    /// verified. A certificate the module will not accept fails with
+    /// `CertificateRejected`, while a failed handshake fails with
+    /// `CommandFailed`. The socket is then used like a TCP socket.
+    pub fn open_tls_socket(
+        &mut self,
+        remote_ip: WifiIpv4,
+        remote_port: u16,
+        ca_cert: Option<&[u8]>,
+    ) -> Result<Socket, WifiError> {
+        let socket = self.allocate_socket()?;
+        info!(
+            "Opening TLS socket {} to {}:{}",
+            socket.index(),
+            remote_ip,
+            remote_port
+        );
+        if let Err(e) = self.start_tls_socket(&socket, remote_ip, remote_port, ca_cert) {
+            self.release_socket(socket);
+            return Err(e);
+        }
+        info!("TLS socket opened");
+        Ok(socket)
+    }
+
+    /// Load the CA certificate, set verification with `P9=` and start `socket`
+    fn start_tls_socket(
+        &mut self,
+        socket: &Socket,
+        remote_ip: WifiIpv4,
+        remote_port: u16,
+        ca_cert: Option<&[u8]>,
+    ) -> Result<(), WifiError> {
+        self.select_socket(socket)?;
+
+        let verify = match ca_cert {
+            Some(cert) => {
+                self.load_ca_certificate(cert)?;
+                2 // Verification required
+            }
+            None => 0, // No verification
+        };
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "P9={}\r", verify).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        self.configure_socket(socket, Protocol::Tls, None, remote_ip, remote_port)
+    }
+
+    /// Write a PEM CA certificate to the module with `PG=0,<len>`
+    fn load_ca_certificate(&mut self, cert: &[u8]) -> Result<(), WifiError> {
+        debug!("Loading {} byte CA certificate", cert.len());
+
+        let mut header: String<16> = String::new();
+        write!(header, "PG=0,{}\r", cert.len()).map_err(|_| WifiError::BufferFull)?;
+        self.send_data_16bit(header.as_bytes(), cert)?;
+
+        match self.read_response_16bit() {
+            Ok(_) => Ok(()),
+            Err(e @ (WifiError::CommandFailed(_) | WifiError::InvalidParameter)) => {
+                warn!("CA certificate rejected: {}", e);
+                Err(WifiError::CertificateRejected)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reserve the lowest free socket slot
+    fn allocate_socket(&mut self) -> Result<Socket, WifiError> {
+        let index = self
+            .sockets_in_use
+            .iter()
+            .position(|in_use| !in_use)
+            .ok_or_else(|| WifiError::command_failed("No free socket"))?;
+        self.sockets_in_use[index] = true;
+        Ok(Socket {
+            index: index as u8,
+            listening: false,
+        })
+    }
+
+    /// Return a socket slot to the free pool
+    fn release_socket(&mut self, socket: Socket) {
+        self.sockets_in_use[socket.index as usize] = false;
+    }
+
+    /// Make `socket` the target of the following socket commands with `P0=`
+    fn select_socket(&mut self, socket: &Socket) -> Result<(), WifiError> {
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "P0={}\r", socket.index).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+        Ok(())
+    }
+
+    /// Send the socket parameters and start it with `P6=1`
+    fn configure_socket(
+        &mut self,
+        socket: &Socket,
+        protocol: Protocol,
+        local_port: Option<u16>,
+        remote_ip: WifiIpv4,
+        remote_port: u16,
+    ) -> Result<(), WifiError> {
+        self.select_socket(socket)?;
+
+        let mut cmd: String<32> = String::new();
+        write!(cmd, "P1={}\r", protocol as u8).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        if let Some(local_port) = local_port {
+            cmd.clear();
+            write!(cmd, "P2={}\r", local_port).map_err(|_| WifiError::BufferFull)?;
+            let _response = self.send_at_command(cmd.as_str())?;
+        }
+
+        cmd.clear();
+        write!(cmd, "P3={}\r", remote_ip).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        cmd.clear();
+        write!(cmd, "P4={}\r", remote_port).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        // Start client; a refused connection or failed TLS handshake is
+        // reported as `CommandFailed`
+        let _response = self.send_at_command("P6=1\r")?;
+        Ok(())
+    }
+
+    /// Listen for inbound TCP connections on `port` using a free slot
+    ///
+    /// Sets the protocol (`P1=0`) and local port (`P2=`), then starts the
+    /// server with `P5=1`. Poll `accept` to find out when a peer connects.
+    pub fn listen(&mut self, port: u16) -> Result<Socket, WifiError> {
+        let mut socket = self.allocate_socket()?;
+        info!("Listening on port {} with socket {}", port, socket.index());
+        if let Err(e) = self.start_server(&socket, port) {
+            self.release_socket(socket);
+            return Err(e);
+        }
+        socket.listening = true;
+        Ok(socket)
+    }
+
+    /// Send the server parameters for `socket` and start it with `P5=1`
+    fn start_server(&mut self, socket: &Socket, port: u16) -> Result<(), WifiError> {
+        self.select_socket(socket)?;
+
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "P1={}\r", Protocol::Tcp as u8).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        cmd.clear();
+        write!(cmd, "P2={}\r", port).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        let _response = self.send_at_command("P5=1\r")?; // Start server
+        Ok(())
+    }
+
+    /// Check whether a peer has connected to the listening `socket`
+    ///
+    /// Returns the peer's address once connected, and `WouldBlock` until
+    /// then. The module holds a single connection per listening socket:
+    /// while a peer is connected this keeps returning it and other clients
+    /// are refused, until it disconnects and the next one can be accepted.
+    /// Exchange data with the peer through `tcp_send`/`tcp_receive` on
+    /// the same socket.
+    pub fn accept(&mut self, socket: &Socket) -> nb::Result<WifiIpv4, WifiError> {
+        if !socket.listening {
+            return Err(nb::Error::Other(WifiError::command_failed(
+                "Socket not listening",
+            )));
+        }
+
+        self.select_socket(socket)?;
+        // `P?` reports the socket settings, including the connected peer
+        let status = self.send_at_command("P?\r")?;
+        match parse_socket_peer(status.as_str()) {
+            Some((peer, port)) => {
+                debug!(
+                    "Peer {}:{} connected to socket {}",
+                    peer,
+                    port,
+                    socket.index()
+                );
+                Ok(peer)
+            }
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Close a socket opened by `open_socket`, `udp_open` or `listen` and free its slot
+    ///
+    /// The slot is freed even if the module fails to acknowledge the close.
+    pub fn close_socket(&mut self, socket: Socket) -> Result<(), WifiError> {
+        info!("Closing socket {}...", socket.index());
+        let result = self.stop_socket(&socket);
+        self.release_socket(socket);
+        result
+    }
+
+    /// Stop the client (`P6=0`) or server (`P5=0`) on `socket`
+    fn stop_socket(&mut self, socket: &Socket) -> Result<(), WifiError> {
+        self.select_socket(socket)?;
+        if socket.listening {
+            let _response = self.send_at_command("P5=0\r")?; // Stop server
+        } else {
+            let _response = self.send_at_command("P6=0\r")?; // Stop client
+        }
+        Ok(())
+    }
+
+    /// Write `data` to `socket`, returning the number of bytes sent
+    ///
+    /// At most `SOCKET_CHUNK_SIZE` bytes are written per call, so callers
+    /// should loop until the whole payload has been sent.
+    pub fn tcp_send(&mut self, socket: &Socket, data: &[u8]) -> Result<usize, WifiError> {
+        self.select_socket(socket)?;
+        self.socket_send(data)
+    }
+
+    /// Read pending data from `socket` into `buf`
+    ///
+    /// Returns the number of bytes read. Zero means no data was available
+    /// or the remote end closed the connection (the module answers `-1`).
+    pub fn tcp_receive(&mut self, socket: &Socket, buf: &mut [u8]) -> Result<usize, WifiError> {
+        self.select_socket(socket)?;
+        self.socket_receive(buf)
+    }
+
+    /// Send `data` as a single datagram on the UDP `socket`
+    ///
+    /// Datagrams longer than `SOCKET_CHUNK_SIZE` are truncated; the returned
+    /// count says how many bytes went out.
+    pub fn udp_send(&mut self, socket: &Socket, data: &[u8]) -> Result<usize, WifiError> {
+        self.select_socket(socket)?;
+        self.socket_send(data)
+    }
+
+    /// Receive a datagram from the UDP `socket` into `buf`
+    ///
+    /// Returns the number of bytes read and, when the module reports it, the
+    /// sender's address and port.
+    pub fn udp_recv(
+        &mut self,
+        socket: &Socket,
+        buf: &mut [u8],
+    ) -> Result<(usize, Option<(WifiIpv4, u16)>), WifiError> {
+        self.select_socket(socket)?;
+        let received = self.socket_receive(buf)?;
+        if received == 0 {
+            return Ok((0, None));
+        }
+
+        // `P?` reports the socket settings, including the last peer
+        let sender = match self.send_at_command("P?\r") {
+            Ok(response) => parse_socket_peer(response.as_str()),
+            Err(e) => {
+                debug!("Could not query datagram sender: {}", e);
+                None
+            }
+        };
+        Ok((received, sender))
+    }
+
+    /// Write up to `SOCKET_CHUNK_SIZE` bytes of `data` with `S3=`
+    fn socket_send(&mut self, data: &[u8]) -> Result<usize, WifiError> {
+        let data = &data[..data.len().min(SOCKET_CHUNK_SIZE)];
+
+        // Write-data sequence: "S3=<len>\r" immediately followed by the payload
+        let mut header: String<16> = String::new();
+        write!(header, "S3={:04}\r", data.len()).map_err(|_| WifiError::BufferFull)?;
+        self.send_data_16bit(header.as_bytes(), data)?;
+
+        // The module replies with the number of bytes it accepted; some
+        // firmware only answers `OK`, which acknowledges the whole payload.
+        // Anything else is an error rather than a guess, so no data is
+        // dropped silently
+        let response = self.read_response_16bit()?;
+        let sent = match response.trim() {
+            "" => data.len(),
+            count => match count.parse() {
+                Ok(sent) if sent <= data.len() => sent,
+                _ => {
+                    warn!("Unexpected reply to socket write: {}", count);
+                    return Err(WifiError::command_failed(count));
+                }
+            },
+        };
+        debug!("Socket accepted {} bytes", sent);
+        Ok(sent)
+    }
+
+    /// Read pending socket data into `buf` with `R1=`/`R0`
+    fn socket_receive(&mut self, buf: &mut [u8]) -> Result<usize, WifiError> {
+        Ok(self.socket_read(buf)?.unwrap_or(0))
+    }
+
+    /// `socket_receive`, but `None` when the module answers `-1`, i.e. the
+    /// socket is closed, rather than folding that into an empty read
+    fn socket_read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, WifiError> {
+        let len = buf.len().min(SOCKET_CHUNK_SIZE);
+        if len == 0 {
+            return Ok(Some(0));
+        }
+
+        // Limit the read packet size so the payload always fits in `buf`
+        let mut cmd: String<16> = String::new();
+        write!(cmd, "R1={}\r", len).map_err(|_| WifiError::BufferFull)?;
+        let _response = self.send_at_command(cmd.as_str())?;
+
+        self.send_command_16bit("R0\r")?;
+        let raw: Vec<u8, SOCKET_RX_BUFFER_SIZE> = self.read_data_16bit()?;
+        let Some(payload) = parse_receive_payload(&raw)? else {
+            return Ok(None);
+        };
+
+        // The module should honour `R1`, but never trust it with our buffer
+        let received = payload.len().min(len);
+        buf[..received].copy_from_slice(&payload[..received]);
+        debug!("Received {} bytes from socket", received);
+        Ok(Some(received))
+    }
+
+    /// Send an arbitrary eS-WiFi command and return the module's reply as is
+    ///
+    /// An escape hatch for commands the typed API does not cover. A missing
+    /// trailing `\r` is added. The reply is returned whole, including the
+    /// final `OK`/`ERROR` line and the prompt, without being validated, so
+    /// the caller decides what counts as success.
+    pub fn raw_command(&mut self, cmd: &str) -> Result<String<RX>, WifiError> {
+        if cmd.ends_with('\r') {
+            self.send_command_16bit(cmd)?;
+        } else {
+            // Append the terminator in the same transaction, without copying
+            self.send_data_16bit(cmd.as_bytes(), b"\r")?;
+        }
+
+        let raw = self.read_reply_bytes(RESPONSE_TIMEOUT_MS)?;
+        let reply = core::str::from_utf8(&raw).map_err(|_| WifiError::MalformedResponse)?;
+        String::try_from(reply).map_err(|_| WifiError::BufferFull)
+    }
+
+    /// Send `command` and read its reply, within `COMMAND_TIMEOUT_MS` overall
+    ///
+    /// Any failure, including a timeout or a rejected command, is returned
+    /// to the caller.
+    fn send_at_command(&mut self, command: &str) -> Result<String<RX>, WifiError> {
+        self.send_at_command_within(command, COMMAND_TIMEOUT_MS)
+    }
+
+    /// `send_at_command` with a caller-chosen deadline for the whole exchange
+    ///
+    /// The deadline is measured on the TIM2 millisecond timebase, so no delay
+    /// provider is needed; once it passes the read fails with `Timeout`.
+    fn send_at_command_within(
+        &mut self,
+        command: &str,
+        timeout_ms: u32,
+    ) -> Result<String<RX>, WifiError> {
+        debug!("Sending AT command: {}", redact_command(command));
+        let started = now_ms();
+        let started_us = micros();
+
+        // Send the command using 16-bit protocol
+        self.send_command_16bit(command)?;
+
+        // Read the response using 16-bit protocol, in whatever time is left
+        let remaining_ms = timeout_ms.saturating_sub(elapsed_ms(started));
+        match self.read_response_within(remaining_ms) {
             Ok(response) => {
-                info!("Response: {}", response.as_str());
+                debug!(
+                    "{} round trip took {}us",
+                    redact_command(command),
+                    elapsed_us(started_us)
+                );
+                info!("Response: {}", redact_reply(command, response.as_str()));
                 Ok(response)
             }
             Err(e) => {
-                warn!("Failed to read response: {}", e);
-                // Return an empty response on error
-                Ok(String::new())
+                // A rejected command carries its reply, which may be the secret
+                let e = match e {
+                    WifiError::CommandFailed(_) if is_redacted_reply(command) => {
+                        WifiError::command_failed(REDACTED)
+                    }
+                    e => e,
+                };
+                warn!(
+                    "Failed to read response to {}: {}",
+                    redact_command(command),
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Whether the reply to `command` carries a secret
+fn is_redacted_reply(command: &str) -> bool {
+    REDACTED_REPLIES.contains(&command.trim())
+}
+
+/// The reply to `command` as it may be logged, masked if it carries a secret
+fn redact_reply<'a>(command: &str, reply: &'a str) -> &'a str {
+    if is_redacted_reply(command) {
+        REDACTED
+    } else {
+        reply
+    }
+}
+
+/// `command` as it may be logged: trimmed, with any secret argument masked
+fn redact_command(command: &str) -> &str {
+    let command = command.trim();
+    REDACTED_COMMANDS
+        .iter()
+        .find(|(prefix, _)| command.starts_with(prefix))
+        .map_or(command, |&(_, masked)| masked)
+}
+
+/// Reject an SSID or password longer than WiFi allows before sending it
+fn check_credential_lengths(ssid: &str, password: &str) -> Result<(), WifiError> {
+    if ssid.len() > SSID_MAX_LEN {
+        warn!(
+            "SSID is {} bytes, over the {} byte limit",
+            ssid.len(),
+            SSID_MAX_LEN
+        );
+        return Err(WifiError::InvalidParameter);
+    }
+    if password.len() > PASSWORD_MAX_LEN {
+        warn!("Password is over the {} byte limit", PASSWORD_MAX_LEN);
+        return Err(WifiError::InvalidParameter);
+    }
+    Ok(())
+}
+
+/// Convert the transmit timestamp of an NTP reply to Unix seconds
+///
+/// The wrapping subtraction keeps the result right after the NTP era rolls
+/// over in 2036.
+fn parse_ntp_reply(packet: &[u8; NTP_PACKET_SIZE]) -> Result<u32, WifiError> {
+    let ntp_secs = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+    if ntp_secs == 0 {
+        return Err(WifiError::MalformedResponse);
+    }
+    Ok(ntp_secs.wrapping_sub(NTP_UNIX_OFFSET))
+}
+
+/// Frame two outgoing bytes as one 16-bit SPI word
+///
+/// The module takes the first byte in the LSB, which the 8-bit SPI frames
+/// send second. An odd-length transfer ends with a `\n` (0x0A) pad byte,
+/// as the reference es-wifi-driver and ST's `es_wifi_io.c` do; every command
+/// is terminated by `\r`, and the module ignores the line feed after it.
+/// After an `S3` payload the pad falls past the announced length, so it is
+/// dropped too.
+fn pack_word(first: u8, second: Option<u8>) -> [u8; 2] {
+    [second.unwrap_or(0x0A), first]
+}
+
+/// Append one block clocked out by `read_data_16bit` to `data`
+///
+/// Words arrive second byte first, as in `read_response_into`. When
+/// `chunk_ended` the module ran out of data within the block and padded the
+/// rest of it with NAKs, which are dropped; a NAK anywhere else is payload
+/// and kept. Returns `false` if a payload byte did not fit in `data`.
+fn append_data_block<const N: usize>(
+    data: &mut Vec<u8, N>,
+    block: &[u8; SPI_BULK_SIZE],
+    chunk_ended: bool,
+) -> bool {
+    let mut bytes = [0u8; SPI_BULK_SIZE];
+    for (swapped, word) in bytes.chunks_exact_mut(2).zip(block.chunks_exact(2)) {
+        swapped.copy_from_slice(&[word[1], word[0]]);
+    }
+
+    let end = if chunk_ended {
+        bytes
+            .iter()
+            .rposition(|&b| b != NAK)
+            .map_or(0, |last| last + 1)
+    } else {
+        bytes.len()
+    };
+    data.extend_from_slice(&bytes[..end]).is_ok()
+}
+
+/// Offset of the body in an HTTP response, just past the blank line
+/// ending the headers
+fn find_header_end(response: &[u8]) -> Option<usize> {
+    response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+/// Status code from the `HTTP/1.1 200 OK` status line starting `response`
+fn parse_http_status(response: &[u8]) -> Option<u16> {
+    let line_end = response.iter().position(|&b| b == b'\r')?;
+    let line = core::str::from_utf8(&response[..line_end]).ok()?;
+    let mut fields = line.split(' ');
+    if !fields.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    fields.next()?.parse().ok()
+}
+
+/// Error for a reply whose last line is `reply` rather than `OK`
+///
+/// A bare `ERROR` carries no detail, so the line before it (`previous`) is
+/// used instead when there is one, as that is where the module explains.
+fn failed_reply(reply: &str, previous: Option<&str>) -> WifiError {
+    let detail = match previous {
+        Some(previous) if reply == "ERROR" => previous,
+        _ => reply,
+    };
+    // Not logged here: the caller knows whether the reply is secret
+    WifiError::from_reply(detail)
+}
+
+/// Validate a text reply and return its data lines
+///
+/// A reply is any number of data lines followed by a final `OK`, framed by
+/// blank lines and the `> ` prompt. Every data line is kept, joined with
+/// `\r\n`; anything other than `OK` as the last line is a failed command.
+/// Only the size of the result bounds the reply, not its number of lines.
+fn parse_response_body<const N: usize>(response: &str) -> Result<String<N>, WifiError> {
+    let lines = || {
+        response
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && *line != ">")
+    };
+
+    let mut from_end = lines();
+    let reply = from_end.next_back().ok_or(WifiError::MalformedResponse)?;
+    if reply != "OK" {
+        return Err(failed_reply(reply, from_end.next_back()));
+    }
+
+    let mut body = String::new();
+    for (i, line) in lines().take(lines().count() - 1).enumerate() {
+        if i > 0 {
+            body.push_str("\r\n").map_err(|_| WifiError::BufferFull)?;
+        }
+        body.push_str(line).map_err(|_| WifiError::BufferFull)?;
+    }
+    Ok(body)
+}
+
+/// Whether `response` is only the `> ` prompt, framed by blank lines
+fn is_bare_prompt(response: &str) -> bool {
+    let mut lines = response
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    lines.next() == Some(">") && lines.next().is_none()
+}
+
+/// Extract the remote address and port from a `P?` socket status reply
+///
+/// The reply is `<protocol>,<local ip>,<local port>,<remote ip>,<remote port>,...`.
+fn parse_socket_peer(status: &str) -> Option<(WifiIpv4, u16)> {
+    let mut fields = status.split(',').map(str::trim).skip(3);
+    let ip = parse_ipv4(fields.next()?)?;
+    let port = fields.next()?.parse().ok()?;
+    if ip.is_unspecified() {
+        return None;
+    }
+    Some((ip, port))
+}
+
+/// Strip the eS-WiFi framing from an `R0` response, returning the payload
+///
+/// A successful read is framed as `\r\n<data>\r\nOK\r\n> `, with no data
+/// when nothing is pending; a read on a closed socket answers `-1`, which is
+/// reported as `None`.
+fn parse_receive_payload(raw: &[u8]) -> Result<Option<&[u8]>, WifiError> {
+    let body = raw.strip_prefix(b"\r\n").unwrap_or(raw);
+    let body = body.strip_suffix(b"> ").unwrap_or(body);
+
+    if let Some(payload) = body.strip_suffix(b"\r\nOK\r\n") {
+        return Ok(Some(payload));
+    }
+    if body.starts_with(b"-1") {
+        debug!("Socket read returned -1, socket closed");
+        return Ok(None);
+    }
+
+    Err(WifiError::command_failed(
+        core::str::from_utf8(body).unwrap_or("").trim(),
+    ))
+}
+
+/// Walk a raw multi-line response, passing each data line to `f`
+///
+/// The last non-empty line is the reply code and must be `OK`. Blank lines,
+/// the trailing `>` prompt and lines that aren't valid UTF-8 (e.g. an SSID
+/// with odd bytes) are skipped.
+fn for_each_data_line<'a>(raw: &'a [u8], mut f: impl FnMut(&'a str)) -> Result<(), WifiError> {
+    let mut pending = None;
+    for line in raw.split(|&b| b == b'\n') {
+        let Ok(line) = core::str::from_utf8(line) else {
+            warn!("Skipping non-UTF-8 response line");
+            continue;
+        };
+        let line = line.trim();
+        if line.is_empty() || line == ">" {
+            continue;
+        }
+
+        // Only hand on a line once we know it isn't the final reply code
+        if let Some(previous) = pending.replace(line) {
+            f(previous);
+        }
+    }
+
+    match pending {
+        Some("OK") => Ok(()),
+        Some(reply) => Err(failed_reply(reply, None)),
+        None => Err(WifiError::MalformedResponse),
+    }
+}
+
+/// Parse one `F0` scan line into an `ApInfo`
+///
+/// Lines look like `#001,"MyNetwork",AA:BB:CC:DD:EE:FF,-57,72.0,Infrastructure,WPA2 AES,2.4GHz,6`.
+/// The SSID is split off last so that commas inside it don't shift the
+/// remaining fields.
+fn parse_scan_line(line: &str) -> Option<ApInfo> {
+    let (_index, rest) = line.strip_prefix('#')?.split_once(',')?;
+    let mut fields = rest.rsplitn(8, ',');
+
+    let channel = fields.next()?.trim().parse().ok()?;
+    let _band = fields.next()?;
+    let security = SecurityMode::from_scan_field(fields.next()?)?;
+    let _network_type = fields.next()?;
+    let _bit_rate = fields.next()?;
+    let rssi = fields.next()?.trim().parse().ok()?;
+    let bssid = parse_mac(fields.next()?.trim())?;
+
+    let ssid_field = fields.next()?.trim();
+    let ssid_field = ssid_field
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(ssid_field);
+    let ssid = String::try_from(ssid_field).ok()?;
+
+    Some(ApInfo {
+        ssid,
+        rssi,
+        channel,
+        security,
+        bssid,
+    })
+}
+
+/// Fields of the `C?` connection status reply
+struct ConnectionInfo {
+    ssid: String<SSID_MAX_LEN>,
+    security: Option<SecurityMode>,
+    dhcp: bool,
+    auto_connect: bool,
+    ip_config: IpConfig,
+    /// Status flag: the module is associated with the access point
+    connected: bool,
+}
+
+/// Parse a `C?` reply into its fields
+///
+/// The reply is every `NetworkSetting` in order, from `<SSID>,<password>`
+/// to `<auto connect>`, followed by `<authentication>,<country>,<status>`.
+/// The SSID and password may themselves contain commas, so the fixed fields
+/// are taken from the end. That leaves `<SSID>,<password>`, which is split
+/// by stripping the known `password` from its end; if the module holds some
+/// other password, e.g. one stored before a reset, the split falls back to
+/// the first comma and an SSID containing a comma comes out cut short.
+fn parse_connection_status(status: &str, password: &str) -> Option<ConnectionInfo> {
+    // Every field after the password, from the security type to the status
+    const FIXED_FIELDS: usize = NetworkSetting::AutoConnect as usize
+        - NetworkSetting::Password as usize
+        + STATUS_TRAILING_FIELDS;
+
+    let mut fixed = [""; FIXED_FIELDS];
+    let mut rest = status.trim();
+    for field in fixed.iter_mut().rev() {
+        let (head, tail) = rest.rsplit_once(',')?;
+        *field = tail.trim();
+        rest = head;
+    }
+    let field =
+        |setting: NetworkSetting| fixed[setting as usize - NetworkSetting::Security as usize];
+    let address = |setting| parse_ipv4(field(setting));
+
+    // Whatever is left is "<SSID>,<password>"
+    let ssid = rest
+        .strip_suffix(password)
+        .and_then(|head| head.strip_suffix(','))
+        .or_else(|| rest.split_once(',').map(|(ssid, _)| ssid))
+        .unwrap_or(rest);
+
+    Some(ConnectionInfo {
+        ssid: String::try_from(ssid.trim()).ok()?,
+        security: field(NetworkSetting::Security)
+            .parse()
+            .ok()
+            .and_then(SecurityMode::from_encryption_code),
+        dhcp: field(NetworkSetting::Dhcp) == "1",
+        auto_connect: field(NetworkSetting::AutoConnect) == "1",
+        ip_config: IpConfig {
+            ip: address(NetworkSetting::IpAddress)?,
+            netmask: address(NetworkSetting::Netmask)?,
+            gateway: address(NetworkSetting::Gateway)?,
+            dns1: address(NetworkSetting::PrimaryDns)?,
+            dns2: address(NetworkSetting::SecondaryDns)?,
+        },
+        connected: fixed[FIXED_FIELDS - 1] == "1",
+    })
+}
+
+/// Copy a required, non-empty response field into a fixed-size string
+fn parse_field<const N: usize>(field: Option<&str>) -> Result<String<N>, WifiError> {
+    let field = field
+        .filter(|f| !f.is_empty())
+        .ok_or(WifiError::MalformedResponse)?;
+    String::try_from(field).map_err(|_| WifiError::BufferFull)
+}
+
+/// Parse a dotted-quad IPv4 address such as `192.168.1.10`
+fn parse_ipv4(text: &str) -> Option<WifiIpv4> {
+    let mut ip = [0u8; 4];
+    let mut octets = text.split('.');
+    for byte in ip.iter_mut() {
+        *byte = octets.next()?.parse().ok()?;
+    }
+
+    // Reject trailing octets
+    if octets.next().is_some() {
+        return None;
+    }
+    Some(WifiIpv4(ip))
+}
+
+/// Parse a `XX:XX:XX:XX:XX:XX` MAC address
+fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut octets = text.split(':');
+    for byte in mac.iter_mut() {
+        let octet = octets.next()?;
+        if octet.len() != 2 || !octet.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        *byte = u8::from_str_radix(octet, 16).ok()?;
+    }
+
+    // Reject trailing octets
+    if octets.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::convert::Infallible;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec as StdVec};
+
+    /// Host stand-in for the ISM43362 behind the SPI bus and control pins
+    ///
+    /// Words clocked in while no reply is pending are taken as a command.
+    /// Releasing chip select after a `\r` queues the next canned reply and
+    /// raises data-ready. A reply is a list of chunks: data-ready drops when
+    /// a chunk runs out, the rest of that transfer is NAK padding, and the
+    /// line rises again for the next chunk on the next delay.
+    #[derive(Default)]
+    struct Module {
+        /// Every byte clocked in, in wire (byte-swapped) order
+        wire: StdVec<u8>,
+        /// Command being received, in natural order
+        command: StdVec<u8>,
+        /// Commands received so far
+        commands: StdVec<StdVec<u8>>,
+        /// Replies for the next commands, each split into chunks
+        replies: VecDeque<StdVec<&'static [u8]>>,
+        /// Remaining chunks of the reply being sent
+        chunks: VecDeque<&'static [u8]>,
+        /// Unsent bytes of the current chunk
+        current: VecDeque<u8>,
+        /// Chip-select edges seen, low and high
+        cs_edges: usize,
+    }
+
+    type Shared = Rc<RefCell<Module>>;
+
+    struct MockSpi(Shared);
+    struct MockCs(Shared);
+    struct MockReady(Shared);
+    struct MockDelay(Shared);
+    struct NoPin;
+
+    impl Transfer<u8> for MockSpi {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            let mut module = self.0.borrow_mut();
+            let replying = !module.current.is_empty();
+            for word in words.chunks_exact_mut(2) {
+                if replying {
+                    let first = module.current.pop_front().unwrap_or(NAK);
+                    let second = module.current.pop_front().unwrap_or(NAK);
+                    word.copy_from_slice(&[second, first]);
+                } else {
+                    module.wire.extend_from_slice(word);
+                    let (first, second) = (word[1], word[0]);
+                    module.command.extend_from_slice(&[first, second]);
+                }
+            }
+            Ok(words)
+        }
+    }
+
+    impl OutputPin for MockCs {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().cs_edges += 1;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut module = self.0.borrow_mut();
+            module.cs_edges += 1;
+            if module.command.contains(&b'\r') {
+                let command = core::mem::take(&mut module.command);
+                module.commands.push(command);
+                let reply = module.replies.pop_front().expect("no reply queued");
+                module.chunks = reply.into();
+                module.next_chunk();
+            }
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockReady {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(!self.0.borrow().current.is_empty())
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    impl DelayUs<u32> for MockDelay {
+        fn delay_us(&mut self, _us: u32) {
+            let mut module = self.0.borrow_mut();
+            if module.current.is_empty() {
+                module.next_chunk();
+            }
+        }
+    }
+
+    impl DelayMs<u32> for MockDelay {
+        fn delay_ms(&mut self, _ms: u32) {}
+    }
+
+    impl OutputPin for NoPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl Module {
+        fn next_chunk(&mut self) {
+            if let Some(chunk) = self.chunks.pop_front() {
+                self.current.extend(chunk.iter().copied());
             }
         }
     }
+
+    type MockModule = WifiModule<MockSpi, MockCs, NoPin, NoPin, MockReady, MockDelay>;
+
+    /// A driver wired to a fresh `Module` that answers with `replies` in turn
+    fn mock_module(replies: &[&[&'static [u8]]]) -> (MockModule, Shared) {
+        let module = Shared::default();
+        module.borrow_mut().replies = replies.iter().map(|chunks| chunks.to_vec()).collect();
+        let pins = WifiPins {
+            cs: MockCs(module.clone()),
+            reset: NoPin,
+            wakeup: NoPin,
+            data_ready: MockReady(module.clone()),
+        };
+        let wifi = WifiModule::new(MockSpi(module.clone()), pins, MockDelay(module.clone()));
+        (wifi, module)
+    }
+
+    #[test]
+    fn ok_reply_returns_data_lines() {
+        let (mut wifi, module) = mock_module(&[&[b"\r\nC4:7F:51:01:02:03\r\nOK\r\n> "]]);
+        let mac = wifi.get_mac_address().unwrap();
+        assert_eq!(mac, [0xC4, 0x7F, 0x51, 0x01, 0x02, 0x03]);
+        assert_eq!(module.borrow().commands, [b"Z5\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn empty_command_is_not_sent() {
+        let (mut wifi, module) = mock_module(&[]);
+        let edges = module.borrow().cs_edges;
+        let result = wifi.send_command_16bit("");
+        assert!(matches!(result, Err(WifiError::InvalidParameter)));
+        let module = module.borrow();
+        assert!(module.wire.is_empty());
+        assert!(module.command.is_empty());
+        assert_eq!(module.cs_edges, edges);
+    }
+
+    #[test]
+    fn empty_ok_reply() {
+        let (mut wifi, _module) = mock_module(&[&[b"\r\nOK\r\n> "]]);
+        assert!(wifi.send_at_command("CD\r").unwrap().is_empty());
+    }
+
+    #[test]
+    fn non_ok_reply_is_an_error() {
+        let (mut wifi, _module) = mock_module(&[&[b"\r\n-1\r\n> "], &[b"\r\nERROR\r\n> "]]);
+        let result = wifi.send_at_command("C0\r");
+        assert!(matches!(result, Err(WifiError::CommandFailed(reply)) if reply == "-1"));
+        let result = wifi.send_at_command("C0\r");
+        assert!(matches!(result, Err(WifiError::CommandFailed(reply)) if reply == "ERROR"));
+    }
+
+    #[test]
+    fn command_deadline_is_enforced() {
+        let (mut wifi, _module) = mock_module(&[OK]);
+        assert!(matches!(
+            wifi.send_at_command_within("CD\r", 0),
+            Err(WifiError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn socket_send_reports_the_accepted_length() {
+        let (mut wifi, _module) = mock_module(&[&[b"\r\n3\r\nOK\r\n> "], OK]);
+        assert_eq!(wifi.socket_send(b"hello").unwrap(), 3);
+        // A bare OK acknowledges the whole payload
+        assert_eq!(wifi.socket_send(b"hello").unwrap(), 5);
+    }
+
+    #[test]
+    fn socket_send_rejects_an_unparseable_reply() {
+        let (mut wifi, _module) = mock_module(&[&[b"\r\nSocket closed\r\nOK\r\n> "]]);
+        let result = wifi.socket_send(b"hello");
+        assert!(matches!(result, Err(WifiError::CommandFailed(reply)) if reply == "Socket closed"));
+    }
+
+    #[test]
+    fn http_exchange_reads_past_empty_polls_until_closed() {
+        let (mut wifi, _module) = mock_module(&[
+            OK,
+            &[b"\r\n4\r\nOK\r\n> "],
+            OK,
+            OK,
+            &[b"\r\nHTTP\r\nOK\r\n> "],
+            OK,
+            OK,
+            &[b"\r\n\r\nOK\r\n> "],
+            OK,
+            OK,
+            &[b"\r\n/1.1\r\nOK\r\n> "],
+            OK,
+            OK,
+            &[b"\r\n-1\r\n> "],
+        ]);
+        let socket = Socket {
+            index: 0,
+            listening: false,
+        };
+        let mut response = [0u8; 16];
+        let received = wifi
+            .http_exchange(&socket, &[b"GET "], &mut response)
+            .unwrap();
+        assert_eq!(&response[..received], b"HTTP/1.1");
+    }
+
+    #[test]
+    fn connect_sends_security_only_as_encryption_type() {
+        let (mut wifi, module) = mock_module(&[OK; 6]);
+        wifi.begin_connect("HomeNet", "hunter22", SecurityMode::Wpa2Psk)
+            .unwrap();
+        while !matches!(wifi.connect_state(), ConnectState::WaitForIp { .. }) {
+            assert!(matches!(wifi.poll_connect(), Err(nb::Error::WouldBlock)));
+        }
+        assert_eq!(
+            sent_commands(&module),
+            [
+                "CD\r",
+                "C1=HomeNet\r",
+                "C2=hunter22\r",
+                "C3=4\r",
+                "CB=3\r",
+                "C0\r"
+            ]
+        );
+    }
+
+    #[test]
+    fn five_ghz_band_is_unsupported() {
+        let (mut wifi, module) = mock_module(&[]);
+        let result = wifi.set_band(Band::Five);
+        assert!(matches!(result, Err(WifiError::Unsupported)));
+        wifi.set_band(Band::TwoPointFour).unwrap();
+        assert!(module.borrow().wire.is_empty());
+    }
+
+    #[test]
+    fn local_validation_fails_without_sending() {
+        let (mut wifi, module) = mock_module(&[]);
+        let long_ssid = "s".repeat(SSID_MAX_LEN + 1);
+        let long_password = "p".repeat(PASSWORD_MAX_LEN + 1);
+        let results = [
+            wifi.begin_connect(&long_ssid, "hunter22", SecurityMode::Wpa2Psk),
+            wifi.begin_connect("HomeNet", &long_password, SecurityMode::Wpa2Psk),
+            wifi.begin_connect("HomeNet", "", SecurityMode::Wpa2Psk),
+            wifi.set_hostname("-sensor"),
+            wifi.set_country_code(b"de"),
+            wifi.set_tx_power(TX_POWER_MAX + 1),
+            wifi.start_access_point("Setup", "hunter22", 14, SecurityMode::Wpa2Psk),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(WifiError::InvalidParameter)));
+        }
+        assert!(module.borrow().wire.is_empty());
+    }
+
+    #[test]
+    fn odd_length_command_is_padded_with_line_feed() {
+        let (mut wifi, module) = mock_module(&[&[b"\r\nOK\r\n> "]]);
+        wifi.send_at_command("CD\r").unwrap();
+        // Each word goes out second byte first; the odd `\r` is paired with 0x0A
+        assert_eq!(module.borrow().wire, [b'D', b'C', 0x0A, b'\r']);
+    }
+
+    #[test]
+    fn even_length_command_is_not_padded() {
+        let (mut wifi, module) = mock_module(&[&[b"\r\nOK\r\n> "]]);
+        wifi.send_at_command("MT=1\r\n").unwrap();
+        assert_eq!(module.borrow().wire, b"TM1=\n\r");
+    }
+
+    #[test]
+    fn odd_length_reply_drops_nak_padding() {
+        // The reply runs out mid-word, so the module pads it with a NAK
+        let (mut wifi, _module) = mock_module(&[&[b"\r\nabc\r\nOK\r\n>"]]);
+        assert_eq!(wifi.send_at_command("I0\r").unwrap().as_str(), "abc");
+    }
+
+    #[test]
+    fn data_reply_in_two_chunks_drops_padding_between_them() {
+        // Each chunk ends mid-block, so the module pads both blocks with NAKs
+        let (mut wifi, _module) =
+            mock_module(&[&[b"\r\n\x01\x15\x02", b"\x03\x15\x04\r\nOK\r\n> "]]);
+        wifi.send_command_16bit("R0\r").unwrap();
+        let data: Vec<u8, 256> = wifi.read_data_16bit().unwrap();
+        assert_eq!(&data[..], b"\r\n\x01\x15\x02\x03\x15\x04\r\nOK\r\n> ");
+        assert_eq!(
+            parse_receive_payload(&data).unwrap(),
+            Some(&b"\x01\x15\x02\x03\x15\x04"[..])
+        );
+    }
+
+    #[test]
+    fn failed_connection_status_leaves_out_the_password() {
+        let (mut wifi, _module) =
+            mock_module(&[&[b"\r\nHomeNet,hunter22,Failed to join\r\nOK\r\n> "]]);
+        let result = wifi.check_connection_status();
+        assert!(
+            matches!(result, Err(WifiError::CommandFailed(reply)) if !reply.contains("hunter22"))
+        );
+    }
+
+    #[test]
+    fn rejected_status_query_leaves_out_the_reply() {
+        let (mut wifi, _module) = mock_module(&[&[b"\r\nHomeNet,hunter22\r\nERROR\r\n> "]]);
+        let result = wifi.send_at_command("C?\r");
+        assert!(matches!(result, Err(WifiError::CommandFailed(reply)) if reply == REDACTED));
+    }
+
+    #[test]
+    fn only_secret_replies_are_redacted() {
+        assert_eq!(redact_reply("C?\r", "HomeNet,hunter22,4"), REDACTED);
+        assert_eq!(
+            redact_reply("Z5\r", "C4:7F:51:01:02:03"),
+            "C4:7F:51:01:02:03"
+        );
+        assert_eq!(redact_command("C2=hunter22\r"), "C2=<redacted>");
+        assert_eq!(redact_command("C1=HomeNet\r"), "C1=HomeNet");
+    }
+
+    const OK: &[&[u8]] = &[b"\r\nOK\r\n> "];
+
+    /// Commands the module received, without the padding of odd lengths
+    fn sent_commands(module: &Shared) -> StdVec<String<64>> {
+        module
+            .borrow()
+            .commands
+            .iter()
+            .map(|command| {
+                let text = core::str::from_utf8(command).unwrap();
+                String::try_from(text.trim_end_matches('\n')).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn network_setting_commands_follow_status_order() {
+        let mut cmd: String<32> = String::new();
+        NetworkSetting::Dhcp.write_command(&mut cmd, 1).unwrap();
+        assert_eq!(cmd.as_str(), "C4=1\r");
+        cmd.clear();
+        NetworkSetting::SecondaryDns
+            .write_command(&mut cmd, WifiIpv4::new(8, 8, 4, 4))
+            .unwrap();
+        assert_eq!(cmd.as_str(), "CA=8.8.4.4\r");
+        cmd.clear();
+        NetworkSetting::AutoConnect
+            .write_command(&mut cmd, 0)
+            .unwrap();
+        assert_eq!(cmd.as_str(), "CC=0\r");
+    }
+
+    #[test]
+    fn static_ip_disables_dhcp_then_sets_addresses() {
+        let (mut wifi, module) = mock_module(&[OK; 5]);
+        wifi.set_static_ip(
+            WifiIpv4::new(192, 168, 1, 50),
+            WifiIpv4::new(255, 255, 255, 0),
+            WifiIpv4::new(192, 168, 1, 1),
+            WifiIpv4::new(1, 1, 1, 1),
+        )
+        .unwrap();
+        assert_eq!(
+            sent_commands(&module),
+            [
+                "C4=0\r",
+                "C6=192.168.1.50\r",
+                "C7=255.255.255.0\r",
+                "C8=192.168.1.1\r",
+                "C9=1.1.1.1\r",
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcp_toggles_c4_and_forgets_the_static_address() {
+        let (mut wifi, module) = mock_module(&[OK; 7]);
+        let ip = WifiIpv4::new(10, 0, 0, 2);
+        wifi.set_static_ip(ip, ip, ip, ip).unwrap();
+        assert_eq!(wifi.static_ip, Some(ip));
+
+        wifi.set_dhcp(false).unwrap();
+        assert_eq!(wifi.static_ip, Some(ip));
+        wifi.set_dhcp(true).unwrap();
+        assert_eq!(wifi.static_ip, None);
+        assert_eq!(sent_commands(&module)[5..], ["C4=0\r", "C4=1\r"]);
+    }
+
+    #[test]
+    fn dns_servers_use_c9_and_ca() {
+        let (mut wifi, module) = mock_module(&[OK; 3]);
+        wifi.set_dns_servers(WifiIpv4::new(1, 1, 1, 1), Some(WifiIpv4::new(8, 8, 8, 8)))
+            .unwrap();
+        wifi.set_dns_servers(WifiIpv4::new(9, 9, 9, 9), None)
+            .unwrap();
+        assert_eq!(
+            sent_commands(&module),
+            ["C9=1.1.1.1\r", "CA=8.8.8.8\r", "C9=9.9.9.9\r"]
+        );
+    }
+
+    #[test]
+    fn hidden_network_is_rejected_before_any_command() {
+        let (mut wifi, module) = mock_module(&[]);
+        let config = ConnectConfig::new("Hidden").hidden(true);
+        assert!(matches!(
+            wifi.begin_connect_with(&config),
+            Err(WifiError::Unsupported)
+        ));
+        assert_eq!(wifi.connect_state, ConnectState::Idle);
+        assert!(module.borrow().commands.is_empty());
+    }
+
+    /// A block as clocked out for `bytes`, padded with NAKs, words swapped
+    fn wire_block(bytes: &[u8]) -> [u8; SPI_BULK_SIZE] {
+        let mut block = [NAK; SPI_BULK_SIZE];
+        block[..bytes.len()].copy_from_slice(bytes);
+        for word in block.chunks_exact_mut(2) {
+            word.swap(0, 1);
+        }
+        block
+    }
+
+    #[test]
+    fn data_block_padding_is_dropped_only_at_chunk_end() {
+        let mut data: Vec<u8, 256> = Vec::new();
+        assert!(append_data_block(&mut data, &wire_block(b"ab\x15c"), true));
+        assert_eq!(&data[..], b"ab\x15c");
+
+        // Mid-chunk the whole block is payload, NAKs included
+        data.clear();
+        let block = wire_block(&[NAK; SPI_BULK_SIZE]);
+        assert!(append_data_block(&mut data, &block, false));
+        assert_eq!(data.len(), SPI_BULK_SIZE);
+    }
+
+    #[test]
+    fn data_block_overflow_is_reported() {
+        let mut data: Vec<u8, 4> = Vec::new();
+        assert!(!append_data_block(&mut data, &wire_block(b"abcdef"), true));
+    }
+
+    #[test]
+    fn connection_status_with_dhcp_address() {
+        let info = parse_connection_status(
+            "HomeNet,hunter22,4,1,0,192.168.1.42,255.255.255.0,192.168.1.1,\
+             192.168.1.1,0.0.0.0,0,1,0,US,1",
+            "hunter22",
+        )
+        .unwrap();
+        assert_eq!(info.ssid.as_str(), "HomeNet");
+        assert_eq!(info.security, Some(SecurityMode::Wpa2Psk));
+        assert!(info.dhcp);
+        assert!(info.auto_connect);
+        assert!(info.connected);
+        assert_eq!(info.ip_config.ip, WifiIpv4::new(192, 168, 1, 42));
+        assert_eq!(info.ip_config.netmask, WifiIpv4::new(255, 255, 255, 0));
+        assert_eq!(info.ip_config.gateway, WifiIpv4::new(192, 168, 1, 1));
+        assert_eq!(info.ip_config.dns1, WifiIpv4::new(192, 168, 1, 1));
+        assert!(info.ip_config.dns2.is_unspecified());
+    }
+
+    #[test]
+    fn connection_status_with_public_address() {
+        // Neither RFC 1918 nor link-local: a carrier-grade NAT address
+        let info = parse_connection_status(
+            "Cafe,,0,1,0,100.64.12.7,255.192.0.0,100.64.0.1,1.1.1.1,8.8.8.8,0,0,0,EU,1",
+            "",
+        )
+        .unwrap();
+        assert_eq!(info.security, Some(SecurityMode::Open));
+        assert_eq!(info.ip_config.ip, WifiIpv4::new(100, 64, 12, 7));
+        assert_eq!(info.ip_config.dns2, WifiIpv4::new(8, 8, 8, 8));
+        assert!(!info.auto_connect);
+    }
+
+    #[test]
+    fn connection_status_while_disconnected() {
+        let info = parse_connection_status(
+            "HomeNet,hunter22,4,1,0,0.0.0.0,0.0.0.0,0.0.0.0,0.0.0.0,0.0.0.0,0,0,0,US,0",
+            "hunter22",
+        )
+        .unwrap();
+        assert!(!info.connected);
+        assert!(info.ip_config.ip.is_unspecified());
+    }
+
+    #[test]
+    fn connection_status_with_commas_in_credentials() {
+        let info = parse_connection_status(
+            "Bob's, Net,pass,word,3,0,0,10.0.0.5,255.0.0.0,10.0.0.1,10.0.0.1,0.0.0.0,0,1,0,US,1",
+            "pass,word",
+        )
+        .unwrap();
+        assert_eq!(info.ssid.as_str(), "Bob's, Net");
+        assert_eq!(info.security, Some(SecurityMode::Wpa2Psk));
+        assert!(!info.dhcp);
+        assert_eq!(info.ip_config.ip, WifiIpv4::new(10, 0, 0, 5));
+
+        // Without the password the split falls back to the first comma
+        let info = parse_connection_status(
+            "Bob's, Net,pass,word,3,0,0,10.0.0.5,255.0.0.0,10.0.0.1,10.0.0.1,0.0.0.0,0,1,0,US,1",
+            "",
+        )
+        .unwrap();
+        assert_eq!(info.ssid.as_str(), "Bob's");
+    }
+
+    #[test]
+    fn connection_status_rejects_short_or_garbled_replies() {
+        assert!(parse_connection_status("", "").is_none());
+        assert!(parse_connection_status("HomeNet,hunter22,4,1", "hunter22").is_none());
+        assert!(parse_connection_status(
+            "HomeNet,hunter22,4,1,0,192.168.1,255.255.255.0,192.168.1.1,\
+             192.168.1.1,0.0.0.0,0,1,0,US,1",
+            "hunter22"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn response_body_keeps_data_lines() {
+        let body: String<64> = parse_response_body("\r\nline one\r\nline two\r\nOK\r\n> ").unwrap();
+        assert_eq!(body.as_str(), "line one\r\nline two");
+
+        let empty: String<64> = parse_response_body("\r\nOK\r\n> ").unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn response_body_reports_failures() {
+        let result: Result<String<64>, _> = parse_response_body("\r\n-1\r\n> ");
+        assert!(matches!(result, Err(WifiError::CommandFailed(reply)) if reply == "-1"));
+
+        // A bare ERROR takes its detail from the line before it
+        let result: Result<String<64>, _> =
+            parse_response_body("\r\nParameter Error\r\nERROR\r\n> ");
+        assert!(matches!(result, Err(WifiError::InvalidParameter)));
+
+        let result: Result<String<64>, _> = parse_response_body("\r\n> ");
+        assert!(matches!(result, Err(WifiError::MalformedResponse)));
+    }
+
+    #[test]
+    fn response_body_line_count_is_bounded_only_by_size() {
+        let mut response: String<256> = String::try_from("\r\n").unwrap();
+        for _ in 0..40 {
+            response.push_str("ab\r\n").unwrap();
+        }
+        response.push_str("OK\r\n> ").unwrap();
+
+        let body: String<256> = parse_response_body(&response).unwrap();
+        assert_eq!(body.lines().count(), 40);
+        let result: Result<String<64>, _> = parse_response_body(&response);
+        assert!(matches!(result, Err(WifiError::BufferFull)));
+    }
+
+    #[test]
+    fn response_body_overflow_is_buffer_full() {
+        let result: Result<String<4>, _> = parse_response_body("\r\nlonger line\r\nOK\r\n> ");
+        assert!(matches!(result, Err(WifiError::BufferFull)));
+    }
+
+    #[test]
+    fn scan_collects_results_without_a_raw_buffer() {
+        let (mut wifi, _module) = mock_module(&[&[
+            b"\r\n#001,\"One\",AA:BB:CC:DD:EE:01,-50,72.0,Infrastructure,WPA2 AES,2.4GHz,1\r\n",
+            b"#002,\"Two\",AA:BB:CC:DD:EE:02,-70,72.0,Infrastructure,Open,2.4GHz,6\r\nOK\r\n> ",
+        ]]);
+        let networks = wifi.scan_networks().unwrap();
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].ssid.as_str(), "One");
+        assert_eq!(networks[1].channel, 6);
+    }
+
+    #[test]
+    fn scan_line_fields() {
+        let ap = parse_scan_line(
+            "#001,\"MyNetwork\",AA:BB:CC:DD:EE:FF,-57,72.0,Infrastructure,WPA2 AES,2.4GHz,6",
+        )
+        .unwrap();
+        assert_eq!(ap.ssid.as_str(), "MyNetwork");
+        assert_eq!(ap.rssi, -57);
+        assert_eq!(ap.channel, 6);
+        assert_eq!(ap.security, SecurityMode::Wpa2Psk);
+        assert_eq!(ap.bssid, [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn scan_line_ssid_with_comma() {
+        let ap = parse_scan_line(
+            "#002,\"Cafe, upstairs\",01:23:45:67:89:ab,-80,1.0,Infrastructure,Open,2.4GHz,11",
+        )
+        .unwrap();
+        assert_eq!(ap.ssid.as_str(), "Cafe, upstairs");
+        assert_eq!(ap.security, SecurityMode::Open);
+        assert_eq!(ap.channel, 11);
+    }
+
+    #[test]
+    fn scan_line_rejects_other_lines() {
+        assert!(parse_scan_line("OK").is_none());
+        assert!(parse_scan_line("#003,\"Short\",AA:BB:CC:DD:EE:FF,-60").is_none());
+        assert!(parse_scan_line(
+            "#004,\"Weird\",AA:BB:CC:DD:EE:FF,-60,72.0,Infrastructure,Mystery,2.4GHz,1"
+        )
+        .is_none());
+    }
 }
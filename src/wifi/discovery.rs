@@ -0,0 +1,137 @@
+//! ISM43362 wiring on the STM32L475 Discovery board (B-L475E-IOT01A)
+//!
+//! Built with the `board-b-l475e-iot01a` feature. The driver itself is
+//! generic over its bus and pins; this module only names the on-board
+//! peripherals and wires them up in `from_discovery`.
+//!
+//! ```text
+//! SPI3_SCK  -> PC10 (ISM43362 SPI_CLK)
+//! SPI3_MISO -> PC11 (ISM43362 SPI_MISO)
+//! SPI3_MOSI -> PC12 (ISM43362 SPI_MOSI)
+//! WiFi_CS   -> PE0  (Chip Select)
+//! WiFi_RST  -> PE8  (Reset)
+//! WiFi_WKUP -> PB13 (Wake up)
+//! WiFi_RDY  -> PE1  (CMD/DATA READY)
+//! ```
+
+use stm32l4xx_hal::{
+    delay::DelayCM,
+    dma::dma2,
+    gpio::{gpiob, gpioc, gpioe, Alternate, Input, Output, PullUp, PushPull},
+    gpio::{PB13, PC10, PC11, PC12, PE0, PE1, PE8},
+    pac::SPI3,
+    rcc::{Clocks, APB1R1},
+    spi::Spi,
+    time::Hertz,
+};
+
+use super::{dma::DmaSpi, WifiModule, WifiPins, RECOMMENDED_SPI_HZ, SPI_BULK_SIZE};
+
+/// SPI peripheral type for WiFi communication
+pub type WifiSpi = Spi<
+    SPI3,
+    (
+        PC10<Alternate<PushPull, 6>>, // SCK
+        PC11<Alternate<PushPull, 6>>, // MISO
+        PC12<Alternate<PushPull, 6>>, // MOSI
+    ),
+>;
+
+/// WiFi control pins as wired on the Discovery board
+pub type DiscoveryWifiPins = WifiPins<
+    PE0<Output<PushPull>>,
+    PE8<Output<PushPull>>,
+    PB13<Output<PushPull>>,
+    PE1<Input<PullUp>>,
+>;
+
+/// WiFi driver as wired on the Discovery board
+pub type DiscoveryWifiModule = WifiModule<
+    DmaSpi,
+    PE0<Output<PushPull>>,
+    PE8<Output<PushPull>>,
+    PB13<Output<PushPull>>,
+    PE1<Input<PullUp>>,
+    DelayCM,
+>;
+
+/// GPIO ports the module's pins are taken from by `from_discovery`
+///
+/// The ports are consumed whole, so anything else on ports B, C or E has
+/// to be wired by hand with `WifiModule::new` instead.
+pub struct DiscoveryPorts {
+    pub gpiob: gpiob::Parts,
+    pub gpioc: gpioc::Parts,
+    pub gpioe: gpioe::Parts,
+}
+
+impl DiscoveryWifiModule {
+    /// Wire up SPI3, the control pins and DMA2 channels 1 (RX) and 2 (TX)
+    ///
+    /// The bus runs in `REQUIRED_SPI_MODE` at `RECOMMENDED_SPI_HZ`, with
+    /// `buffer` as the DMA bounce buffer. The data-ready EXTI interrupt is
+    /// left to the caller, through `pins.data_ready`, as is unmasking
+    /// DMA2_CH1 and EXTI1 with handlers that call
+    /// `dma::notify_transfer_complete` and `notify_data_ready`.
+    pub fn from_discovery(
+        spi3: SPI3,
+        ports: DiscoveryPorts,
+        dma: (dma2::C1, dma2::C2),
+        buffer: &'static mut [u8; SPI_BULK_SIZE],
+        clocks: Clocks,
+        apb1r1: &mut APB1R1,
+    ) -> Self {
+        let DiscoveryPorts {
+            mut gpiob,
+            mut gpioc,
+            mut gpioe,
+        } = ports;
+
+        // SPI3 pins are alternate function 6
+        let sck = gpioc.pc10.into_alternate_push_pull(
+            &mut gpioc.moder,
+            &mut gpioc.otyper,
+            &mut gpioc.afrh,
+        );
+        let miso = gpioc.pc11.into_alternate_push_pull(
+            &mut gpioc.moder,
+            &mut gpioc.otyper,
+            &mut gpioc.afrh,
+        );
+        let mosi = gpioc.pc12.into_alternate_push_pull(
+            &mut gpioc.moder,
+            &mut gpioc.otyper,
+            &mut gpioc.afrh,
+        );
+
+        let pins = WifiPins {
+            cs: gpioe
+                .pe0
+                .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper),
+            reset: gpioe
+                .pe8
+                .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper),
+            wakeup: gpiob
+                .pb13
+                .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper),
+            data_ready: gpioe
+                .pe1
+                .into_pull_up_input(&mut gpioe.moder, &mut gpioe.pupdr),
+        };
+
+        let spi = Spi::spi3(
+            spi3,
+            (sck, miso, mosi),
+            Self::REQUIRED_SPI_MODE,
+            Hertz::from_raw(RECOMMENDED_SPI_HZ),
+            clocks,
+            apb1r1,
+        );
+        let (rx_channel, tx_channel) = dma;
+        let spi = DmaSpi::new(spi, rx_channel, tx_channel, buffer);
+
+        // The microsecond chip-select timing uses a cycle-count delay,
+        // leaving SysTick to the application
+        WifiModule::new(spi, pins, DelayCM::new(clocks))
+    }
+}
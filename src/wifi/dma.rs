@@ -0,0 +1,106 @@
+//! DMA-driven SPI3 transport for bulk socket payloads
+//!
+//! `DmaSpi` implements the blocking `Transfer` trait the driver is generic
+//! over. A transfer of exactly `SPI_BULK_SIZE` bytes, which the driver only
+//! issues for `S3` payloads and `R0`/`F0` data reads, is clocked by DMA2
+//! channels 1 (RX) and 2 (TX) while the core sleeps in `WFI`. Every other
+//! transfer, including the whole command/response control path, is polled
+//! as before.
+
+use cortex_m::{asm::wfi, interrupt, peripheral::NVIC};
+use embedded_hal::blocking::spi::Transfer;
+use stm32l4xx_hal::{
+    dma::{dma2, Event, TransferDma},
+    pac::Interrupt,
+    spi,
+};
+
+use super::{WifiSpi, SPI_BULK_SIZE};
+
+/// SPI3 together with the DMA2 channels that serve its RX and TX requests
+pub struct DmaSpi {
+    /// Polled bus and idle channels; only `None` while a DMA transfer runs
+    parts: Option<(WifiSpi, dma2::C1, dma2::C2)>,
+    /// Bounce buffer the DMA reads from and writes back into
+    buffer: Option<&'static mut [u8; SPI_BULK_SIZE]>,
+}
+
+impl DmaSpi {
+    /// Pair `spi` with its DMA channels and a static bounce buffer
+    ///
+    /// The DMA2_CH1 interrupt handler must call `notify_transfer_complete`,
+    /// which wakes the core once a bulk transfer has finished.
+    pub fn new(
+        spi: WifiSpi,
+        mut rx_channel: dma2::C1,
+        tx_channel: dma2::C2,
+        buffer: &'static mut [u8; SPI_BULK_SIZE],
+    ) -> Self {
+        rx_channel.listen(Event::TransferComplete);
+        Self {
+            parts: Some((spi, rx_channel, tx_channel)),
+            buffer: Some(buffer),
+        }
+    }
+
+    /// Exchange one `SPI_BULK_SIZE` block by DMA, sleeping until it is done
+    fn transfer_bulk(&mut self, words: &mut [u8]) {
+        let (spi, rx_channel, tx_channel) = self
+            .parts
+            .take()
+            .expect("SPI parts are restored after every transfer");
+        let buffer = self
+            .buffer
+            .take()
+            .expect("bounce buffer is restored after every transfer");
+        buffer.copy_from_slice(words);
+
+        let transfer = spi.with_rxtx_dma(rx_channel, tx_channel).transfer(buffer);
+
+        // A completion left pending by an earlier transfer must not wake us
+        NVIC::unpend(Interrupt::DMA2_CH1);
+        unsafe {
+            NVIC::unmask(Interrupt::DMA2_CH1);
+        }
+        // WFI still wakes on a pending interrupt with interrupts disabled,
+        // so completion cannot slip in between the check and the sleep
+        while !transfer.is_done() {
+            interrupt::free(|_| {
+                if !transfer.is_done() {
+                    wfi();
+                }
+            });
+        }
+
+        let (buffer, dma) = transfer.wait();
+        words.copy_from_slice(&buffer[..]);
+        self.buffer = Some(buffer);
+        self.parts = Some(dma.split());
+    }
+}
+
+impl Transfer<u8> for DmaSpi {
+    type Error = spi::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], spi::Error> {
+        if words.len() == SPI_BULK_SIZE {
+            self.transfer_bulk(words);
+            return Ok(words);
+        }
+
+        let (spi, _, _) = self
+            .parts
+            .as_mut()
+            .expect("SPI parts are restored after every transfer");
+        spi.transfer(words)
+    }
+}
+
+/// Wake the driver from a bulk transfer
+///
+/// Call this from the DMA2_CH1 interrupt handler. The line is masked here
+/// and unmasked again by the next bulk transfer, so the transfer-complete
+/// flag can stay set until the driver clears it.
+pub fn notify_transfer_complete() {
+    NVIC::mask(Interrupt::DMA2_CH1);
+}
@@ -0,0 +1,544 @@
+//! Minimal MQTT 3.1.1 client over the module's TCP sockets
+//!
+//! Enough for telemetry: a clean-session `CONNECT`, `PUBLISH` at QoS 0 or 1,
+//! `SUBSCRIBE`, and `PINGREQ` keep-alives. Packets are encoded by hand into
+//! fixed-size buffers and moved with `tcp_send`/`tcp_receive`; there is no
+//! session persistence, retained-message or will support.
+//!
+//! The client is driven by calling `mqtt_poll` regularly, which sends the
+//! keep-alive and hands incoming messages to a callback. Messages that arrive
+//! while a call is waiting for its own acknowledgement are dropped.
+
+use embedded_hal::{
+    blocking::{delay::DelayUs, spi::Transfer},
+    digital::v2::{InputPin, OutputPin},
+};
+use heapless::Vec;
+
+use super::{Protocol, Socket, WifiError, WifiIpv4, WifiModule};
+use crate::logging::{debug, info, warn};
+use crate::time::{elapsed_ms, now_ms};
+
+/// Largest MQTT packet sent or received, fixed header included
+pub const MQTT_PACKET_SIZE: usize = 512;
+
+/// How long to wait for the broker to acknowledge a request
+pub const MQTT_TIMEOUT_MS: u32 = 5_000;
+
+/// Bytes pulled from the socket per `tcp_receive`
+const RECEIVE_CHUNK_SIZE: usize = 128;
+
+// Control packet types, in the high nibble of the first header byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Delivery guarantee for a publish or subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    /// Fire and forget
+    AtMostOnce = 0,
+    /// Acknowledged by the receiver with `PUBACK`
+    AtLeastOnce = 1,
+}
+
+/// Connection to a broker opened with `mqtt_connect`
+pub struct MqttSession {
+    /// TCP socket carrying the session
+    socket: Socket,
+    /// Keep-alive negotiated in `CONNECT`; zero disables `PINGREQ`
+    keep_alive_secs: u16,
+    /// When a packet was last sent, to pace the keep-alive
+    last_sent_ms: u32,
+    /// Identifier for the next QoS 1 publish or subscription
+    next_packet_id: u16,
+    /// Bytes received but not yet consumed as a whole packet
+    rx: Vec<u8, MQTT_PACKET_SIZE>,
+    /// Bytes of an oversize packet still to be dropped as they arrive
+    discard: usize,
+}
+
+impl MqttSession {
+    /// Hand out a packet identifier, skipping the reserved zero
+    fn take_packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    /// Find the first complete packet in the received bytes
+    ///
+    /// Returns its header byte, the offset of its body and the body length,
+    /// or `None` while more bytes are needed. A packet too large to buffer
+    /// is dropped, along with the rest of it still to arrive, and a header
+    /// that can't be decoded drops everything received; either fails once.
+    fn next_packet(&mut self) -> Result<Option<(u8, usize, usize)>, WifiError> {
+        let (header, start, len) = match parse_fixed_header(&self.rx) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("Dropping {} MQTT bytes with a bad header", self.rx.len());
+                self.rx.clear();
+                return Err(e);
+            }
+        };
+
+        if start + len > MQTT_PACKET_SIZE {
+            warn!(
+                "Dropping {}-byte MQTT packet, over {} bytes",
+                start + len,
+                MQTT_PACKET_SIZE
+            );
+            self.discard = start + len - self.rx.len();
+            self.rx.clear();
+            return Err(WifiError::BufferFull);
+        }
+        Ok((self.rx.len() >= start + len).then_some((header, start, len)))
+    }
+
+    /// Drop the first `len` received bytes
+    fn consume(&mut self, len: usize) {
+        let remaining = self.rx.len() - len;
+        self.rx.copy_within(len.., 0);
+        self.rx.truncate(remaining);
+    }
+}
+
+impl<SPI, CS, RST, WAKE, RDY, DELAY, const RX: usize> WifiModule<SPI, CS, RST, WAKE, RDY, DELAY, RX>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    RST: OutputPin,
+    WAKE: OutputPin,
+    RDY: InputPin,
+    DELAY: DelayUs<u32>,
+{
+    /// Open a clean MQTT session with the broker at `broker_ip:port`
+    ///
+    /// The broker drops the session if it hears nothing for 1.5x
+    /// `keep_alive_secs`, so `mqtt_poll` must be called more often than that.
+    /// A refused connection fails with `CommandFailed`.
+    pub fn mqtt_connect(
+        &mut self,
+        broker_ip: WifiIpv4,
+        port: u16,
+        client_id: &str,
+        keep_alive_secs: u16,
+    ) -> Result<MqttSession, WifiError> {
+        info!("MQTT connecting to {}:{} as {}", broker_ip, port, client_id);
+        let socket = self.open_socket(Protocol::Tcp, broker_ip, port)?;
+        let mut session = MqttSession {
+            socket,
+            keep_alive_secs,
+            last_sent_ms: now_ms(),
+            next_packet_id: 1,
+            rx: Vec::new(),
+            discard: 0,
+        };
+
+        if let Err(e) = self.mqtt_handshake(&mut session, client_id) {
+            let _ = self.close_socket(session.socket);
+            return Err(e);
+        }
+        info!("MQTT session established");
+        Ok(session)
+    }
+
+    /// Send `CONNECT` and check the broker's `CONNACK`
+    fn mqtt_handshake(
+        &mut self,
+        session: &mut MqttSession,
+        client_id: &str,
+    ) -> Result<(), WifiError> {
+        // Protocol name, level 4 (3.1.1), clean session flag and keep-alive
+        let mut packet = Vec::new();
+        push_fixed_header(&mut packet, CONNECT << 4, 10 + 2 + client_id.len())?;
+        push_string(&mut packet, "MQTT")?;
+        push_bytes(&mut packet, &[4, 0x02])?;
+        push_bytes(&mut packet, &session.keep_alive_secs.to_be_bytes())?;
+        push_string(&mut packet, client_id)?;
+        self.mqtt_send(session, &packet)?;
+
+        let ack = self.mqtt_wait_for(session, CONNACK, None)?;
+        match ack.get(1) {
+            Some(0) => Ok(()),
+            Some(&code) => {
+                warn!("MQTT broker refused the connection: code {}", code);
+                Err(WifiError::command_failed("MQTT connection refused"))
+            }
+            None => Err(WifiError::MalformedResponse),
+        }
+    }
+
+    /// Publish `payload` on `topic`
+    ///
+    /// With `QoS::AtLeastOnce` this waits for the broker's `PUBACK`.
+    pub fn mqtt_publish(
+        &mut self,
+        session: &mut MqttSession,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), WifiError> {
+        debug!("MQTT publish {} bytes to {}", payload.len(), topic);
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(session.take_packet_id()),
+        };
+
+        let id_len = if packet_id.is_some() { 2 } else { 0 };
+        let mut packet = Vec::new();
+        push_fixed_header(
+            &mut packet,
+            (PUBLISH << 4) | ((qos as u8) << 1),
+            2 + topic.len() + id_len + payload.len(),
+        )?;
+        push_string(&mut packet, topic)?;
+        if let Some(id) = packet_id {
+            push_bytes(&mut packet, &id.to_be_bytes())?;
+        }
+        push_bytes(&mut packet, payload)?;
+        self.mqtt_send(session, &packet)?;
+
+        if let Some(id) = packet_id {
+            self.mqtt_wait_for(session, PUBACK, Some(id))?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to `topic`, which may contain `+`/`#` wildcards
+    ///
+    /// Matching messages are handed to the callback of `mqtt_poll`. Fails
+    /// with `CommandFailed` if the broker rejects the subscription.
+    pub fn mqtt_subscribe(
+        &mut self,
+        session: &mut MqttSession,
+        topic: &str,
+        qos: QoS,
+    ) -> Result<(), WifiError> {
+        info!("MQTT subscribing to {}", topic);
+        let id = session.take_packet_id();
+
+        // SUBSCRIBE has reserved flags 0b0010
+        let mut packet = Vec::new();
+        push_fixed_header(
+            &mut packet,
+            (SUBSCRIBE << 4) | 0x02,
+            2 + 2 + topic.len() + 1,
+        )?;
+        push_bytes(&mut packet, &id.to_be_bytes())?;
+        push_string(&mut packet, topic)?;
+        push_bytes(&mut packet, &[qos as u8])?;
+        self.mqtt_send(session, &packet)?;
+
+        let ack = self.mqtt_wait_for(session, SUBACK, Some(id))?;
+        match ack.get(2) {
+            Some(&granted) if granted < 0x80 => Ok(()),
+            Some(_) => {
+                warn!("MQTT broker rejected subscription to {}", topic);
+                Err(WifiError::command_failed("MQTT subscribe rejected"))
+            }
+            None => Err(WifiError::MalformedResponse),
+        }
+    }
+
+    /// Keep the session alive and deliver incoming messages
+    ///
+    /// Sends `PINGREQ` once half the keep-alive has passed without traffic,
+    /// reads whatever the socket has pending, and calls `on_message` with the
+    /// topic and payload of every complete `PUBLISH`, acknowledging QoS 1
+    /// messages.
+    pub fn mqtt_poll(
+        &mut self,
+        session: &mut MqttSession,
+        mut on_message: impl FnMut(&str, &[u8]),
+    ) -> Result<(), WifiError> {
+        let keep_alive_ms = u32::from(session.keep_alive_secs) * 1000;
+        if keep_alive_ms > 0 && elapsed_ms(session.last_sent_ms) >= keep_alive_ms / 2 {
+            debug!("MQTT keep-alive ping");
+            self.mqtt_send(session, &[PINGREQ << 4, 0])?;
+        }
+
+        self.mqtt_receive(session)?;
+        while let Some((header, start, len)) = session.next_packet()? {
+            let mut ack_id = None;
+            if header >> 4 == PUBLISH {
+                let qos = (header >> 1) & 0x03;
+                match parse_publish(&session.rx[start..start + len], qos) {
+                    Ok((topic, packet_id, payload)) => {
+                        on_message(topic, payload);
+                        ack_id = packet_id;
+                    }
+                    Err(e) => {
+                        warn!("Dropping malformed MQTT publish");
+                        session.consume(start + len);
+                        return Err(e);
+                    }
+                }
+            } else if header >> 4 != PINGRESP {
+                debug!("Ignoring MQTT packet type {}", header >> 4);
+            }
+            session.consume(start + len);
+
+            if let Some(id) = ack_id {
+                let [high, low] = id.to_be_bytes();
+                self.mqtt_send(session, &[PUBACK << 4, 2, high, low])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `DISCONNECT` and close the session's socket
+    pub fn mqtt_disconnect(&mut self, mut session: MqttSession) -> Result<(), WifiError> {
+        info!("MQTT disconnecting");
+        let sent = self.mqtt_send(&mut session, &[DISCONNECT << 4, 0]);
+        let closed = self.close_socket(session.socket);
+        sent?;
+        closed
+    }
+
+    /// Write a whole packet to the session's socket
+    fn mqtt_send(&mut self, session: &mut MqttSession, packet: &[u8]) -> Result<(), WifiError> {
+        let mut sent = 0;
+        while sent < packet.len() {
+            match self.tcp_send(&session.socket, &packet[sent..])? {
+                0 => return Err(WifiError::command_failed("Send stalled")),
+                written => sent += written,
+            }
+        }
+        session.last_sent_ms = now_ms();
+        Ok(())
+    }
+
+    /// Append whatever the socket has pending to the session's buffer
+    fn mqtt_receive(&mut self, session: &mut MqttSession) -> Result<(), WifiError> {
+        let room = (session.rx.capacity() - session.rx.len()).min(RECEIVE_CHUNK_SIZE);
+        if room == 0 {
+            warn!("MQTT packet exceeds {} bytes", MQTT_PACKET_SIZE);
+            return Err(WifiError::BufferFull);
+        }
+
+        let mut chunk = [0u8; RECEIVE_CHUNK_SIZE];
+        let received = self.tcp_receive(&session.socket, &mut chunk[..room])?;
+        let skipped = received.min(session.discard);
+        session.discard -= skipped;
+        session
+            .rx
+            .extend_from_slice(&chunk[skipped..received])
+            .map_err(|_| WifiError::BufferFull)
+    }
+
+    /// Wait for a packet of `packet_type`, optionally carrying `packet_id`
+    ///
+    /// Returns the first bytes of its body. Anything else that arrives in
+    /// the meantime is discarded.
+    fn mqtt_wait_for(
+        &mut self,
+        session: &mut MqttSession,
+        packet_type: u8,
+        packet_id: Option<u16>,
+    ) -> Result<Vec<u8, 4>, WifiError> {
+        let started = now_ms();
+        loop {
+            while let Some((header, start, len)) = session.next_packet()? {
+                let body = &session.rx[start..start + len];
+                let id_matches =
+                    packet_id.is_none_or(|id| body.get(..2) == Some(&id.to_be_bytes()[..]));
+                let found = (header >> 4 == packet_type && id_matches)
+                    .then(|| Vec::from_slice(&body[..body.len().min(4)]).unwrap_or_default());
+                if found.is_none() {
+                    debug!("Discarding MQTT packet type {}", header >> 4);
+                }
+                session.consume(start + len);
+                if let Some(body) = found {
+                    return Ok(body);
+                }
+            }
+
+            if elapsed_ms(started) > MQTT_TIMEOUT_MS {
+                warn!("No MQTT acknowledgement within {} ms", MQTT_TIMEOUT_MS);
+                return Err(WifiError::Timeout);
+            }
+            self.feed_watchdog();
+            self.mqtt_receive(session)?;
+        }
+    }
+}
+
+/// Decode the fixed header at the start of `buf`
+///
+/// Returns the header byte, the offset of the body and the body length, or
+/// `None` while more header bytes are needed. The body may not have fully
+/// arrived yet.
+fn parse_fixed_header(buf: &[u8]) -> Result<Option<(u8, usize, usize)>, WifiError> {
+    let Some(&header) = buf.first() else {
+        return Ok(None);
+    };
+
+    // Remaining length: up to four bytes, seven bits each, low bits first
+    let mut len = 0;
+    for (i, &byte) in buf[1..].iter().take(4).enumerate() {
+        len |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((header, 2 + i, len)));
+        }
+    }
+    if buf.len() > 4 {
+        return Err(WifiError::MalformedResponse);
+    }
+    Ok(None)
+}
+
+/// Split the body of a `PUBLISH` into topic, packet identifier and payload
+fn parse_publish(body: &[u8], qos: u8) -> Result<(&str, Option<u16>, &[u8]), WifiError> {
+    let topic_len = usize::from(u16::from_be_bytes(
+        body.get(..2)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(WifiError::MalformedResponse)?,
+    ));
+    let topic = body
+        .get(2..2 + topic_len)
+        .and_then(|t| core::str::from_utf8(t).ok())
+        .ok_or(WifiError::MalformedResponse)?;
+
+    let mut rest = &body[2 + topic_len..];
+    let mut packet_id = None;
+    if qos > 0 {
+        let id = rest.get(..2).ok_or(WifiError::MalformedResponse)?;
+        packet_id = Some(u16::from_be_bytes([id[0], id[1]]));
+        rest = &rest[2..];
+    }
+    Ok((topic, packet_id, rest))
+}
+
+/// Start a packet with its header byte and remaining length
+fn push_fixed_header(
+    packet: &mut Vec<u8, MQTT_PACKET_SIZE>,
+    header: u8,
+    mut remaining: usize,
+) -> Result<(), WifiError> {
+    push_bytes(packet, &[header])?;
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        push_bytes(packet, &[byte])?;
+        if remaining == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Append a length-prefixed UTF-8 string
+fn push_string(packet: &mut Vec<u8, MQTT_PACKET_SIZE>, s: &str) -> Result<(), WifiError> {
+    let len = u16::try_from(s.len()).map_err(|_| WifiError::BufferFull)?;
+    push_bytes(packet, &len.to_be_bytes())?;
+    push_bytes(packet, s.as_bytes())
+}
+
+fn push_bytes(packet: &mut Vec<u8, MQTT_PACKET_SIZE>, bytes: &[u8]) -> Result<(), WifiError> {
+    packet
+        .extend_from_slice(bytes)
+        .map_err(|_| WifiError::BufferFull)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(rx: &[u8]) -> MqttSession {
+        MqttSession {
+            socket: Socket {
+                index: 0,
+                listening: false,
+            },
+            keep_alive_secs: 0,
+            last_sent_ms: 0,
+            next_packet_id: 1,
+            rx: Vec::from_slice(rx).unwrap(),
+            discard: 0,
+        }
+    }
+
+    #[test]
+    fn remaining_length_round_trips_at_byte_boundaries() {
+        let cases: [(usize, &[u8]); 4] = [
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+        ];
+        for (remaining, encoded) in cases {
+            let mut packet = Vec::new();
+            push_fixed_header(&mut packet, PUBLISH << 4, remaining).unwrap();
+            assert_eq!(&packet[1..], encoded);
+            assert_eq!(
+                parse_fixed_header(&packet).unwrap(),
+                Some((PUBLISH << 4, 1 + encoded.len(), remaining))
+            );
+        }
+    }
+
+    #[test]
+    fn publish_at_qos0_has_no_packet_id() {
+        let body = b"\x00\x03a/bhello";
+        assert_eq!(
+            parse_publish(body, 0).unwrap(),
+            ("a/b", None, &b"hello"[..])
+        );
+    }
+
+    #[test]
+    fn publish_at_qos1_carries_packet_id() {
+        let body = b"\x00\x03a/b\x12\x34hello";
+        assert_eq!(
+            parse_publish(body, 1).unwrap(),
+            ("a/b", Some(0x1234), &b"hello"[..])
+        );
+    }
+
+    #[test]
+    fn truncated_input_waits_or_fails() {
+        assert_eq!(parse_fixed_header(&[]).unwrap(), None);
+        assert_eq!(parse_fixed_header(&[PUBLISH << 4]).unwrap(), None);
+        assert_eq!(parse_fixed_header(&[PUBLISH << 4, 0x80]).unwrap(), None);
+        assert!(session(&[PUBLISH << 4, 5, 0, 1])
+            .next_packet()
+            .unwrap()
+            .is_none());
+
+        assert!(parse_publish(b"\x00", 0).is_err());
+        assert!(parse_publish(b"\x00\x05a/b", 0).is_err());
+        assert!(parse_publish(b"\x00\x03a/b\x12", 1).is_err());
+    }
+
+    #[test]
+    fn bad_remaining_length_drops_the_buffer() {
+        let mut session = session(&[PUBLISH << 4, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        assert!(matches!(
+            session.next_packet(),
+            Err(WifiError::MalformedResponse)
+        ));
+        assert!(session.rx.is_empty());
+        assert_eq!(session.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn oversize_packet_is_discarded() {
+        // 600-byte body behind a 3-byte header, of which 8 bytes arrived
+        let mut session = session(&[PUBLISH << 4, 0xD8, 0x04, 0, 3, b'a', b'/', b'b']);
+        assert!(matches!(session.next_packet(), Err(WifiError::BufferFull)));
+        assert!(session.rx.is_empty());
+        assert_eq!(session.discard, 603 - 8);
+        assert_eq!(session.next_packet().unwrap(), None);
+    }
+}
@@ -0,0 +1,105 @@
+//! `embedded-nal` network stack implementation for the WiFi module
+//!
+//! Lets TCP clients written against `embedded_nal::TcpClientStack` (MQTT,
+//! HTTP, ...) run on top of the module's socket commands. The module is
+//! driven with blocking commands, so only `receive` ever reports
+//! `WouldBlock`.
+
+use core::net::SocketAddr;
+
+use embedded_hal::{
+    blocking::{delay::DelayUs, spi::Transfer},
+    digital::v2::{InputPin, OutputPin},
+};
+use embedded_nal::{TcpClientStack, TcpError, TcpErrorKind};
+
+use super::{Protocol, Socket, WifiError, WifiIpv4, WifiModule};
+
+/// Socket slot handed out by `TcpClientStack::socket`
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TcpSocket {
+    /// Module slot reserved for this socket
+    socket: Socket,
+    /// Whether `connect` has opened the socket on the module
+    connected: bool,
+}
+
+impl TcpError for WifiError {
+    fn kind(&self) -> TcpErrorKind {
+        match self {
+            WifiError::NotConnected => TcpErrorKind::PipeClosed,
+            _ => TcpErrorKind::Other,
+        }
+    }
+}
+
+impl<SPI, CS, RST, WAKE, RDY, DELAY, const RX: usize> TcpClientStack
+    for WifiModule<SPI, CS, RST, WAKE, RDY, DELAY, RX>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    RST: OutputPin,
+    WAKE: OutputPin,
+    RDY: InputPin,
+    DELAY: DelayUs<u32>,
+{
+    type TcpSocket = TcpSocket;
+    type Error = WifiError;
+
+    fn socket(&mut self) -> Result<TcpSocket, WifiError> {
+        let socket = self.allocate_socket()?;
+        Ok(TcpSocket {
+            socket,
+            connected: false,
+        })
+    }
+
+    fn connect(&mut self, socket: &mut TcpSocket, remote: SocketAddr) -> nb::Result<(), WifiError> {
+        if !self.is_connected() {
+            return Err(nb::Error::Other(WifiError::NotConnected));
+        }
+        let SocketAddr::V4(remote) = remote else {
+            return Err(nb::Error::Other(WifiError::command_failed(
+                "IPv6 not supported",
+            )));
+        };
+
+        let ip = WifiIpv4::from(remote.ip().octets());
+        self.configure_socket(&socket.socket, Protocol::Tcp, None, ip, remote.port())?;
+        socket.connected = true;
+        Ok(())
+    }
+
+    fn send(&mut self, socket: &mut TcpSocket, buffer: &[u8]) -> nb::Result<usize, WifiError> {
+        if !socket.connected {
+            return Err(nb::Error::Other(WifiError::NotConnected));
+        }
+        Ok(self.tcp_send(&socket.socket, buffer)?)
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut TcpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, WifiError> {
+        if !socket.connected {
+            return Err(nb::Error::Other(WifiError::NotConnected));
+        }
+        // The module reports "nothing yet" and "closed" the same way, so
+        // treat an empty read as data still to come
+        match self.tcp_receive(&socket.socket, buffer)? {
+            0 if !buffer.is_empty() => Err(nb::Error::WouldBlock),
+            received => Ok(received),
+        }
+    }
+
+    fn close(&mut self, socket: TcpSocket) -> Result<(), WifiError> {
+        if socket.connected {
+            self.close_socket(socket.socket)
+        } else {
+            self.release_socket(socket.socket);
+            Ok(())
+        }
+    }
+}